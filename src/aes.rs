@@ -0,0 +1,245 @@
+//! # AES 分组密码
+//! 纯软件实现，支持 128/192/256 位密钥，仅供块设备加密使用
+//! 查表实现，不抵抗缓存计时侧信道
+
+use core::{ptr::write_volatile, sync::atomic::{Ordering, compiler_fence}};
+
+const BLOCK : usize = 16;
+/// AES-256 的轮数
+const MAX_ROUNDS : usize = 14;
+
+static SBOX : [u8;256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+static INV_SBOX : [u8;256] = make_inverse(&SBOX);
+
+const fn make_inverse(sbox : &[u8;256])->[u8;256] {
+    let mut table = [0;256];
+    let mut i = 0;
+    while i < 256 {
+        table[sbox[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+static RCON : [u8;10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+/// GF(2^8) 上乘以 x
+fn xtime(v : u8)->u8 {
+    (v << 1) ^ if v & 0x80 != 0 {0x1b} else {0}
+}
+
+fn mul(mut a : u8, mut b : u8)->u8 {
+    let mut rt = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            rt ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    rt
+}
+
+pub struct Aes {
+    round_keys : [[u8;BLOCK];MAX_ROUNDS + 1],
+    rounds : usize,
+}
+
+impl Aes {
+    /// 密钥长度须为 16、24 或 32 字节
+    pub fn new(key : &[u8])->Option<Self> {
+        let nk = key.len() / 4;
+        if key.len() != 16 && key.len() != 24 && key.len() != 32 {
+            return None;
+        }
+        let rounds = nk + 6;
+        let mut words = [[0u8;4];4 * (MAX_ROUNDS + 1)];
+        for (i, word) in key.chunks(4).enumerate() {
+            words[i].copy_from_slice(word);
+        }
+        for i in nk..4 * (rounds + 1) {
+            let mut t = words[i - 1];
+            if i % nk == 0 {
+                t = [SBOX[t[1] as usize] ^ RCON[i / nk - 1], SBOX[t[2] as usize], SBOX[t[3] as usize], SBOX[t[0] as usize]];
+            }
+            else if nk > 6 && i % nk == 4 {
+                for b in t.iter_mut() {
+                    *b = SBOX[*b as usize];
+                }
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - nk][j] ^ t[j];
+            }
+        }
+        let mut round_keys = [[0;BLOCK];MAX_ROUNDS + 1];
+        for (i, key) in round_keys.iter_mut().enumerate().take(rounds + 1) {
+            for j in 0..4 {
+                key[j * 4..j * 4 + 4].copy_from_slice(&words[i * 4 + j]);
+            }
+        }
+        Some(Self {
+            round_keys,
+            rounds,
+        })
+    }
+
+    /// 用 volatile 写清零轮密钥，避免被优化掉
+    pub fn wipe(&mut self) {
+        for key in self.round_keys.iter_mut() {
+            for b in key.iter_mut() {
+                unsafe {write_volatile(b, 0)}
+            }
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+
+    pub fn encrypt(&self, block : &mut [u8;BLOCK]) {
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1..self.rounds {
+            sub_bytes(block, &SBOX);
+            shift_rows(block);
+            mix_columns(block);
+            add_round_key(block, &self.round_keys[round]);
+        }
+        sub_bytes(block, &SBOX);
+        shift_rows(block);
+        add_round_key(block, &self.round_keys[self.rounds]);
+    }
+
+    pub fn decrypt(&self, block : &mut [u8;BLOCK]) {
+        add_round_key(block, &self.round_keys[self.rounds]);
+        for round in (1..self.rounds).rev() {
+            inv_shift_rows(block);
+            sub_bytes(block, &INV_SBOX);
+            add_round_key(block, &self.round_keys[round]);
+            inv_mix_columns(block);
+        }
+        inv_shift_rows(block);
+        sub_bytes(block, &INV_SBOX);
+        add_round_key(block, &self.round_keys[0]);
+    }
+}
+
+fn add_round_key(block : &mut [u8;BLOCK], key : &[u8;BLOCK]) {
+    for (b, k) in block.iter_mut().zip(key.iter()) {
+        *b ^= k;
+    }
+}
+
+fn sub_bytes(block : &mut [u8;BLOCK], table : &[u8;256]) {
+    for b in block.iter_mut() {
+        *b = table[*b as usize];
+    }
+}
+
+/// 状态按列存放，第 r 行第 c 列位于 r + 4c
+fn shift_rows(block : &mut [u8;BLOCK]) {
+    let old = *block;
+    for r in 1..4 {
+        for c in 0..4 {
+            block[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+fn inv_shift_rows(block : &mut [u8;BLOCK]) {
+    let old = *block;
+    for r in 1..4 {
+        for c in 0..4 {
+            block[r + 4 * ((c + r) % 4)] = old[r + 4 * c];
+        }
+    }
+}
+
+fn mix_columns(block : &mut [u8;BLOCK]) {
+    for col in block.chunks_mut(4) {
+        let a = [col[0], col[1], col[2], col[3]];
+        let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+        for i in 0..4 {
+            col[i] = a[i] ^ all ^ xtime(a[i] ^ a[(i + 1) % 4]);
+        }
+    }
+}
+
+fn inv_mix_columns(block : &mut [u8;BLOCK]) {
+    for col in block.chunks_mut(4) {
+        let a = [col[0], col[1], col[2], col[3]];
+        for i in 0..4 {
+            col[i] = mul(a[i], 0x0e) ^ mul(a[(i + 1) % 4], 0x0b) ^ mul(a[(i + 2) % 4], 0x0d) ^ mul(a[(i + 3) % 4], 0x09);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN : [u8;BLOCK] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+    ];
+
+    /// FIPS-197 附录 C，密钥为 00 01 02 ... 依次递增的字节
+    fn check(key_len : usize, cipher : [u8;BLOCK]) {
+        let mut key = [0;32];
+        for (i, k) in key.iter_mut().enumerate() {
+            *k = i as u8;
+        }
+        let aes = Aes::new(&key[..key_len]).unwrap();
+        let mut block = PLAIN;
+        aes.encrypt(&mut block);
+        assert_eq!(block, cipher);
+        aes.decrypt(&mut block);
+        assert_eq!(block, PLAIN);
+    }
+
+    #[test]
+    fn aes128() {
+        check(16, [
+            0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4, 0xc5, 0x5a,
+        ]);
+    }
+
+    #[test]
+    fn aes192() {
+        check(24, [
+            0xdd, 0xa9, 0x7c, 0xa4, 0x86, 0x4c, 0xdf, 0xe0, 0x6e, 0xaf, 0x70, 0xa0, 0xec, 0x0d, 0x71, 0x91,
+        ]);
+    }
+
+    #[test]
+    fn aes256() {
+        check(32, [
+            0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49, 0x60, 0x89,
+        ]);
+    }
+
+    #[test]
+    fn bad_key() {
+        assert!(Aes::new(&[0;20]).is_none());
+    }
+
+    #[test]
+    fn wipe() {
+        let mut aes = Aes::new(&[0x5a;32]).unwrap();
+        aes.wipe();
+        assert!(aes.round_keys.iter().flatten().all(|&b| b == 0));
+    }
+}
@@ -2,19 +2,29 @@
 //! 
 //! 2021年3月30日 zg
 
-use core::{mem::size_of};
+use core::{cmp::{max, min}, convert::TryInto, mem::size_of, ptr::{addr_of_mut, copy_nonoverlapping, read_volatile, write_volatile}, slice::from_raw_parts_mut};
 use tisu_memory::{MemoryOp};
 use tisu_sync::Bool;
 use tisu_sync::SpinMutex;
 
 use crate::{InterruptResult, IoResult, config::{
-		InterruptError,
+		CacheMode,
 		InterruptOk,
 		IoError,
-		PAGE_SIZE
+		PAGE_SIZE,
+		RequestId,
+		SECTOR_SIZE,
+		Zone,
+		ZoneInfo,
+		ZoneState,
+		ZoneType,
+		ZonedModel,
 	}, pool::Pool, queue::BlockFlag, require::{
+		AsyncBlockDriver,
 		BlockDriver,
-		Driver
+		Clock,
+		Driver,
+		HartId,
 	}};
 
 use super::{
@@ -27,10 +37,218 @@ use super::{
 	}
 };
 
+/// 块设备特性位
+#[repr(u32)]
+#[derive(Clone, Copy)]
+pub enum BlockFeature {
+	SizeMax = 1,
+	SegMax = 2,
+	ReadOnly = 5,
+	BlkSize = 6,
+	Flush = 9,
+	Topology = 10,
+	/// 可通过配置空间切换缓存模式
+	ConfigWce = 11,
+	/// 多个请求队列
+	MQ = 12,
+	Discard = 13,
+	WriteZeroes = 14,
+	SecureErase = 16,
+	/// 区域化设备
+	Zoned = 17,
+}
+
+impl BlockFeature {
+	pub fn v(self)->u32 {
+		1 << self as u32
+	}
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Geometry {
+	cylinders : u16,
+	heads : u8,
+	sectors : u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Topology {
+	/// 每个物理块包含 2^exp 个逻辑块
+	physical_block_exp : u8,
+	alignment_offset : u8,
+	min_io_size : u16,
+	opt_io_size : u32,
+}
+
+/// 设备配置空间，位于寄存器偏移 0x100 处
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Config {
+	capacity : u64,
+	size_max : u32,
+	seg_max : u32,
+	geometry : Geometry,
+	blk_size : u32,
+	topology : Topology,
+	/// 1 为 writeback，0 为 writethrough
+	writeback : u8,
+	unused0 : u8,
+	num_queues : u16,
+	max_discard_sectors : u32,
+	max_discard_seg : u32,
+	discard_sector_alignment : u32,
+	max_write_zeroes_sectors : u32,
+	max_write_zeroes_seg : u32,
+	write_zeroes_may_unmap : u8,
+	unused1 : [u8;3],
+	max_secure_erase_sectors : u32,
+	max_secure_erase_seg : u32,
+	secure_erase_sector_alignment : u32,
+	zone_sectors : u32,
+	max_open_zones : u32,
+	max_active_zones : u32,
+	max_append_sectors : u32,
+	write_granularity : u32,
+	model : u8,
+	unused2 : [u8;3],
+}
+
+/// 设备序列号长度
+pub const SERIAL_LEN : usize = 20;
+
+/// 最多使用的请求队列数
+const MAX_QUEUES : usize = 8;
+
+/// 单个请求最多携带的数据段
+const MAX_SEGMENTS : usize = 32;
+
+/// 每个队列供带期限请求使用的 bounce 缓冲区页数
+const BOUNCE_PAGES : usize = 8;
+
+/// 请求完成后设备写回的状态
+const VIRTIO_BLK_S_OK : u8 = 0;
+const VIRTIO_BLK_S_IOERR : u8 = 1;
+const VIRTIO_BLK_S_UNSUPP : u8 = 2;
+const VIRTIO_BLK_S_ZONE_INVALID_CMD : u8 = 3;
+const VIRTIO_BLK_S_ZONE_UNALIGNED_WP : u8 = 4;
+const VIRTIO_BLK_S_ZONE_OPEN_RESOURCE : u8 = 5;
+const VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE : u8 = 6;
+
+/// write zeroes 时允许设备回收空间
+const WRITE_ZEROES_FLAG_UNMAP : u32 = 1;
+
+/// discard、write zeroes、secure erase 请求的数据段
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RangeSegment {
+	sector : u64,
+	num_sectors : u32,
+	flags : u32,
+}
+
+/// 区域报告头部与每个区域描述的长度
+const ZONE_REPORT_HEADER : usize = 64;
+const ZONE_DESC_SIZE : usize = 64;
+
+/// 延迟直方图的桶数，第 i 桶统计延迟在 [2^i, 2^(i+1)) 个时钟单位内的请求
+pub const LATENCY_BUCKETS : usize = 32;
+
+/// 块设备读写统计
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BlockStats {
+	pub reads : usize,
+	pub writes : usize,
+	pub flushes : usize,
+	/// discard、write zeroes 等其余请求
+	pub others : usize,
+	pub read_bytes : usize,
+	pub write_bytes : usize,
+	/// 设备返回 VIRTIO_BLK_S_IOERR 的次数
+	pub io_errors : usize,
+	/// 设备返回 VIRTIO_BLK_S_UNSUPP 的次数
+	pub unsupported : usize,
+	/// 其余无法识别的状态
+	pub other_errors : usize,
+	/// 已提交但设备尚未完成的请求数
+	pub in_flight : usize,
+	/// 设置时钟后才会统计
+	pub latency : [usize;LATENCY_BUCKETS],
+}
+
+impl BlockStats {
+	fn add(&mut self, other : &Self) {
+		self.reads += other.reads;
+		self.writes += other.writes;
+		self.flushes += other.flushes;
+		self.others += other.others;
+		self.read_bytes += other.read_bytes;
+		self.write_bytes += other.write_bytes;
+		self.io_errors += other.io_errors;
+		self.unsupported += other.unsupported;
+		self.other_errors += other.other_errors;
+		self.in_flight += other.in_flight;
+		for i in 0..LATENCY_BUCKETS {
+			self.latency[i] += other.latency[i];
+		}
+	}
+
+	fn submit(&mut self, blktype : BlockFlag) {
+		match blktype {
+			BlockFlag::In => self.reads += 1,
+			BlockFlag::Out | BlockFlag::ZoneAppend => self.writes += 1,
+			BlockFlag::Flush => self.flushes += 1,
+			_ => self.others += 1,
+		}
+		self.in_flight += 1;
+	}
+
+	fn complete(&mut self, rq : &Request, now : Option<u64>) {
+		self.in_flight -= 1;
+		match rq.result() {
+			Ok(()) => match rq.header.blktype {
+				BlockFlag::In => self.read_bytes += rq.bytes,
+				BlockFlag::Out | BlockFlag::ZoneAppend => self.write_bytes += rq.bytes,
+				_ => {}
+			}
+			Err(IoError::DeviceError) => self.io_errors += 1,
+			Err(IoError::Unsupported) => self.unsupported += 1,
+			Err(_) => self.other_errors += 1,
+		}
+		if let Some(now) = now {
+			let time = now.saturating_sub(rq.start);
+			let idx = if time == 0 {0} else {63 - time.leading_zeros() as usize};
+			self.latency[min(idx, LATENCY_BUCKETS - 1)] += 1;
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RequestState {
+	/// 空闲，可以放入新请求
+	Free,
+	/// 已交给设备，等待中断
+	Pending,
+	/// 设备已处理完，等待调用者取回结果
+	Done,
+	/// 等待超时，调用者已放弃，设备归还后直接回收
+	Abandoned,
+}
+
 struct Request {
 	pub header: Header,
-	pub data:   *mut u8,
 	pub status: u8,
+	pub state : RequestState,
+	pub tag : usize,
+	/// 数据段总字节数
+	pub bytes : usize,
+	/// 提交时的时钟
+	pub start : u64,
+	/// zone append 完成后由设备写入数据所在的扇区
+	pub append_sector : u64,
+	/// 请求占用的 bounce 缓冲区，请求被放弃时由设备归还后回收
+	pub bounce : Option<usize>,
 	pub lock : SpinMutex,
 }
 
@@ -38,8 +256,13 @@ impl Clone for Request{
     fn clone(&self) -> Self {
         Self {
             header: self.header,
-            data: self.data,
             status: self.status,
+            state: self.state,
+            tag: self.tag,
+            bytes: self.bytes,
+            start: self.start,
+            append_sector: self.append_sector,
+            bounce: self.bounce,
             lock: SpinMutex::new(),
 		}
     }
@@ -51,81 +274,731 @@ impl Default for Request {
     fn default() -> Self {
 		Self{
 		    header: Header::default(),
-		    data: 0 as *mut u8,
 		    status: 0,
+		    state: RequestState::Free,
+		    tag: 0,
+		    bytes: 0,
+		    start: 0,
+		    append_sector: 0,
+		    bounce: None,
 		    lock: SpinMutex::new(),
 		}
     }
 }
 
 impl Request {
-	pub fn new(data:&[u8],offset:usize,write:bool)->Self {
-		// let addr = heap.alloc_kernel_memory(size_of::<Request>()).unwrap();
-		// let rt = addr as *mut Request;
-		// let rq = unsafe {&mut *rt};
+	pub fn new(blktype : BlockFlag, sector : u64)->Self {
 		Self {
 			header : Header {
-				blktype: if write {BlockFlag::Out} else {BlockFlag::In},
+				blktype,
 				reserved: 0,
-				sector: (offset / 512) as u64
+				sector,
 			},
-			data : data as *const [u8] as *const u8 as *mut u8,
 			status : 111,
+			state : RequestState::Pending,
+			tag : 0,
+			bytes : 0,
+			start : 0,
+			append_sector : 0,
+			bounce : None,
 		    lock: SpinMutex::new(),
 		}
-		// rq.header.blktype = if write {BlockFlag::Out} else {BlockFlag::In};
-		// rq.header.sector = (offset / 512) as u64;
-		// rq.header.reserved = 0;
-		// rq.data = buffer;
-		// rq.status = 111;
+	}
 
-		// rt
+	/// 将设备写回的状态转换为结果
+	pub fn result(&self)->IoResult {
+		match unsafe {read_volatile(&self.status)} {
+			VIRTIO_BLK_S_OK => Ok(()),
+			VIRTIO_BLK_S_IOERR => Err(IoError::DeviceError),
+			VIRTIO_BLK_S_UNSUPP => Err(IoError::Unsupported),
+			VIRTIO_BLK_S_ZONE_INVALID_CMD => Err(IoError::ZoneInvalid),
+			VIRTIO_BLK_S_ZONE_UNALIGNED_WP => Err(IoError::ZoneUnalignedWrite),
+			VIRTIO_BLK_S_ZONE_OPEN_RESOURCE => Err(IoError::ZoneOpenResource),
+			VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE => Err(IoError::ZoneActiveResource),
+			_ => Err(IoError::RequestError),
+		}
 	}
 }
 
-pub struct Block {
-	header : &'static mut VirtHeader,
+/// 一个请求队列及其请求池，各队列互不加锁
+struct BlockQueue {
 	queue : &'static mut VirtQueue,
 	request_pool : Pool<Request>,
 	mutex : SpinMutex,
+	tag : usize,
+	stats : BlockStats,
+	/// 轮询模式，等待时自行处理完成的请求
+	polling : bool,
+	/// BOUNCE_PAGES 页连续内存
+	bounce : *mut u8,
+	bounce_busy : [bool;BOUNCE_PAGES],
+}
+
+impl BlockQueue {
+	pub fn new(queue : &'static mut VirtQueue, bounce : *mut u8)->Self {
+		Self {
+			queue,
+			request_pool : Pool::default(),
+			mutex : SpinMutex::new(),
+			tag : 0,
+			stats : BlockStats::default(),
+			polling : false,
+			bounce,
+			bounce_busy : [false;BOUNCE_PAGES],
+		}
+	}
+
+	/// 占用一页空闲的 bounce 缓冲区，返回其编号，跳过仍被超时请求占用的页
+	pub fn alloc_bounce(&mut self)->Option<usize> {
+		self.mutex.lock();
+		let rt = self.bounce_busy.iter().position(|busy| !busy);
+		if let Some(idx) = rt {
+			self.bounce_busy[idx] = true;
+		}
+		self.mutex.unlock();
+		rt
+	}
+
+	pub fn free_bounce(&mut self, idx : usize) {
+		self.mutex.lock();
+		self.bounce_busy[idx] = false;
+		self.mutex.unlock();
+	}
+
+	pub fn bounce_address(&self, idx : usize)->*mut u8 {
+		unsafe {self.bounce.add(idx * PAGE_SIZE)}
+	}
+
+	/// 将请求放入队列，返回请求下标和标签，由调用者通知设备
+	/// segments 为各数据段的地址和长度，可以为空
+	pub fn push(&mut self, v : Request, segments : &[(u64, u32)], now : Option<u64>)->Result<(usize, usize), IoError> {
+		// zone append 在状态前多一个由设备写入的扇区号
+		let append = matches!(v.header.blktype, BlockFlag::ZoneAppend);
+		let num = segments.len() + if append {3} else {2};
+		self.mutex.lock();
+		// 链头同时是请求池下标，已完成但未取回结果的请求仍占用请求池
+		let pool = &mut self.request_pool;
+		let idx = match self.queue.alloc_chain(num, |head| pool.get(head as usize).state == RequestState::Free) {
+			Some(idx) => idx as usize,
+			None => {
+				self.mutex.unlock();
+				return Err(IoError::QueueFull);
+			}
+		};
+		self.tag = self.tag.wrapping_add(1);
+		let rq = self.request_pool.replace_ref(idx, v);
+		rq.tag = self.tag;
+		rq.bytes = segments.iter().map(|s| s.1 as usize).sum();
+		rq.start = now.unwrap_or(0);
+		rq.lock.lock();
+		let blktype = rq.header.blktype;
+		let header = &rq.header as *const Header;
+		let status = &rq.status as *const u8;
+		let append_sector = &rq.append_sector as *const u64;
+		let flag = if blktype.device_write() {DescFlag::Write as u16} else {0};
+		let mut desc = self.queue.set_desc(idx as u16, header as u64, size_of::<Header>() as u32, 0);
+		for &(addr, len) in segments {
+			desc = self.queue.set_desc(desc, addr, len, flag);
+		}
+		if append {
+			desc = self.queue.set_desc(desc, append_sector as u64, size_of::<u64>() as u32, DescFlag::Write as u16);
+		}
+		self.queue.set_desc(desc, status as u64, 1, DescFlag::Write as u16);
+		self.queue.add_avail(idx as u16);
+		self.stats.submit(blktype);
+		self.mutex.unlock();
+		Ok((idx, self.tag))
+	}
+
+	/// 处理设备已完成的请求，now 为完成时的时钟
+	pub fn complete(&mut self, now : Option<u64>) {
+		loop {
+			// 轮询时等待者与中断处理可能同时进入，须在锁内检查
+			self.mutex.lock();
+			if !self.queue.is_pending() {
+				self.mutex.unlock();
+				break;
+			}
+			let elem = self.queue.next_elem();
+			self.queue.free_desc(elem.id as u16);
+			// 无论状态字节是否出错，都交给等待者取回并回收
+			let rq = self.request_pool.get(elem.id as usize);
+			match rq.state {
+				RequestState::Pending => {
+					self.stats.complete(rq, now);
+					unsafe {write_volatile(&mut rq.state, RequestState::Done)}
+					rq.lock.unlock();
+				}
+				RequestState::Abandoned => {
+					self.stats.complete(rq, now);
+					if let Some(idx) = rq.bounce {
+						self.bounce_busy[idx] = false;
+					}
+					unsafe {write_volatile(&mut rq.state, RequestState::Free)}
+					rq.lock.unlock();
+				}
+				_ => {}
+			}
+			self.mutex.unlock();
+		}
+	}
+
+	pub fn try_wait(&mut self, idx : usize, tag : usize)->Option<IoResult> {
+		let rq = self.request_pool.get(idx);
+		let state = unsafe {read_volatile(&rq.state)};
+		if rq.tag != tag || state == RequestState::Free || state == RequestState::Abandoned {
+			return Some(Err(IoError::RequestError));
+		}
+		if state != RequestState::Done {
+			return None;
+		}
+		rq.state = RequestState::Free;
+		Some(rq.result())
+	}
+
+	pub fn wait(&mut self, idx : usize, tag : usize, clock : Option<&dyn Clock>)->IoResult {
+		let rq = self.request_pool.get(idx);
+		if rq.tag == tag && !self.polling {
+			rq.lock.lock();
+			rq.lock.unlock();
+		}
+		loop {
+			if self.polling {
+				self.complete(clock.map(|clock| clock.now()));
+			}
+			if let Some(rt) = self.try_wait(idx, tag) {
+				return rt;
+			}
+		}
+	}
+
+	/// 等待直到时钟到达 deadline，超时则将请求标为放弃
+	pub fn wait_until(&mut self, idx : usize, tag : usize, clock : &dyn Clock, deadline : u64)->IoResult {
+		loop {
+			if self.polling {
+				self.complete(Some(clock.now()));
+			}
+			if let Some(rt) = self.try_wait(idx, tag) {
+				return rt;
+			}
+			if clock.now() >= deadline {
+				self.mutex.lock();
+				let rq = self.request_pool.get(idx);
+				// 加锁后再检查一次，请求可能恰好完成
+				let abandoned = rq.tag == tag && rq.state == RequestState::Pending;
+				if abandoned {
+					rq.state = RequestState::Abandoned;
+				}
+				self.mutex.unlock();
+				if abandoned {
+					return Err(IoError::Timeout);
+				}
+			}
+		}
+	}
+
+	/// 放弃请求，已完成的立即回收，未完成的在设备归还后回收
+	pub fn cancel(&mut self, idx : usize, tag : usize) {
+		self.mutex.lock();
+		let rq = self.request_pool.get(idx);
+		if rq.tag == tag {
+			match rq.state {
+				RequestState::Pending => rq.state = RequestState::Abandoned,
+				RequestState::Done => rq.state = RequestState::Free,
+				_ => {}
+			}
+		}
+		self.mutex.unlock();
+	}
+}
+
+pub struct Block {
+	header : &'static mut VirtHeader,
+	queues : &'static mut [BlockQueue],
+	hart : Option<&'static dyn HartId>,
+	clock : Option<&'static dyn Clock>,
+	features : u32,
+	/// 缓存的容量（扇区）与逻辑块大小，配置变化中断时刷新
+	capacity : usize,
+	block_size : usize,
 	pub int : Bool,
 }
 
 impl Block {
     pub fn new(header : *mut VirtHeader, memory : &mut impl MemoryOp)->Self {
-		let num = (size_of::<VirtQueue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-		let queue = memory.kernel_page(num).unwrap() as *mut VirtQueue;
 		let header = unsafe {&mut *(header)};
-		header.set_feature(!(1 << BlockFlag::ReadOnly as u32)).unwrap();
-		header.set_ring_size(VIRTIO_RING_SIZE as u32).unwrap();
+		let features = header.set_feature(
+			BlockFeature::SizeMax.v() |
+			BlockFeature::SegMax.v() |
+			BlockFeature::ReadOnly.v() |
+			BlockFeature::BlkSize.v() |
+			BlockFeature::Flush.v() |
+			BlockFeature::Topology.v() |
+			BlockFeature::ConfigWce.v() |
+			BlockFeature::MQ.v() |
+			BlockFeature::Discard.v() |
+			BlockFeature::WriteZeroes.v() |
+			BlockFeature::SecureErase.v() |
+			BlockFeature::Zoned.v()
+		).unwrap();
+		let count = if features & BlockFeature::MQ.v() != 0 {
+			let config = unsafe {read_volatile(header.config_address() as *const Config)};
+			(config.num_queues as usize).clamp(1, MAX_QUEUES)
+		}
+		else {
+			1
+		};
 		header.set_page_size(PAGE_SIZE as u32);
-		header.set_pfn(0, (queue as u32) / PAGE_SIZE as u32);
+		let num = (size_of::<BlockQueue>() * count).div_ceil(PAGE_SIZE);
+		let queues = memory.kernel_page(num).unwrap() as *mut BlockQueue;
+		let num = (size_of::<VirtQueue>() + PAGE_SIZE - 1) / PAGE_SIZE;
+		for i in 0..count {
+			let queue = memory.kernel_page(num).unwrap() as *mut VirtQueue;
+			header.set_queue(i as u32, VIRTIO_RING_SIZE as u32, (queue as u32) / PAGE_SIZE as u32).unwrap();
+			let bounce = memory.kernel_page(BOUNCE_PAGES).unwrap();
+			unsafe {queues.add(i).write(BlockQueue::new(&mut *queue, bounce))}
+		}
 		header.driver_ok();
 
-		let rt = Self {
+		let mut rt = Self {
 			header,
-			queue : unsafe {&mut *queue},
-			request_pool : Pool::default(),
-			mutex : SpinMutex::new(),
+			queues : unsafe {from_raw_parts_mut(queues, count)},
+			hart : None,
+			clock : None,
+			features,
+			capacity : 0,
+			block_size : SECTOR_SIZE,
 			int : Bool::new(),
 		};
+		rt.refresh_config();
 		rt
     }
+
+	/// 重新读取容量与块大小，设备配置变化时由 handler 调用
+	pub fn refresh_config(&mut self) {
+		loop {
+			let generation = self.header.config_generation();
+			let config = self.config();
+			self.capacity = config.capacity as usize;
+			self.block_size = match config.blk_size {
+				size if size != 0 && self.has_feature(BlockFeature::BlkSize) => size as usize,
+				_ => SECTOR_SIZE,
+			};
+			if self.header.config_generation() == generation {
+				break;
+			}
+		}
+	}
+
+	/// 设置 hart 编号来源，之后请求提交到当前 hart 对应的队列
+	pub fn set_hart(&mut self, hart : &'static dyn HartId) {
+		self.hart = Some(hart);
+	}
+
+	/// 设置时钟来源，之后统计请求延迟
+	pub fn set_clock(&mut self, clock : &'static dyn Clock) {
+		self.clock = Some(clock);
+	}
+
+	fn now(&self)->Option<u64> {
+		self.clock.map(|clock| clock.now())
+	}
+
+	/// 所有队列的统计之和
+	pub fn stats(&self)->BlockStats {
+		let mut stats = BlockStats::default();
+		for queue in self.queues.iter() {
+			stats.add(&queue.stats);
+		}
+		stats
+	}
+
+	/// 当前 hart 使用的队列
+	fn current_queue(&self)->usize {
+		match self.hart {
+			Some(hart) => hart.hart_id() % self.queues.len(),
+			None => 0,
+		}
+	}
+
+	fn config(&self)->Config {
+		unsafe {read_volatile(self.header.config_address() as *const Config)}
+	}
+
+	fn has_feature(&self, feature : BlockFeature)->bool {
+		self.features & feature.v() != 0
+	}
+
+	/// 第一个按物理块对齐的逻辑块的字节偏移
+	pub fn alignment_offset(&self)->usize {
+		if self.has_feature(BlockFeature::Topology) {
+			self.config().topology.alignment_offset as usize * self.block_size()
+		}
+		else {
+			0
+		}
+	}
+
+	/// 建议的最小读写字节数
+	pub fn min_io_size(&self)->usize {
+		if self.has_feature(BlockFeature::Topology) {
+			max(self.config().topology.min_io_size as usize, 1) * self.block_size()
+		}
+		else {
+			self.block_size()
+		}
+	}
+
+	/// 建议的最优读写字节数，0 表示设备未给出
+	pub fn opt_io_size(&self)->usize {
+		if self.has_feature(BlockFeature::Topology) {
+			self.config().topology.opt_io_size as usize * self.block_size()
+		}
+		else {
+			0
+		}
+	}
+
+	/// 当前写缓存模式
+	pub fn cache_mode(&self)->CacheMode {
+		let writeback = if self.has_feature(BlockFeature::ConfigWce) {
+			self.config().writeback != 0
+		}
+		else {
+			self.has_feature(BlockFeature::Flush)
+		};
+		if writeback {CacheMode::WriteBack} else {CacheMode::WriteThrough}
+	}
+
+	/// 切换写缓存模式，需要设备支持 VIRTIO_BLK_F_CONFIG_WCE
+	pub fn set_cache_mode(&mut self, mode : CacheMode)->IoResult {
+		if !self.has_feature(BlockFeature::ConfigWce) {
+			return Err(IoError::Unsupported);
+		}
+		let config = self.header.config_address() as *mut Config;
+		let v = if mode == CacheMode::WriteBack {1} else {0};
+		unsafe {write_volatile(addr_of_mut!((*config).writeback), v)}
+		Ok(())
+	}
+
+	/// 读取设备序列号，不足 20 字节时以 0 填充
+	pub fn serial(&mut self)->Result<[u8;SERIAL_LEN], IoError> {
+		let mut serial = [0;SERIAL_LEN];
+		let data = &mut serial as *mut [u8] as *mut u8 as u64;
+		let v = Request::new(BlockFlag::GetId, 0);
+		let id = self.submit(v, &[(data, SERIAL_LEN as u32)])?;
+		self.wait(id)?;
+		Ok(serial)
+	}
+
+	/// 将请求放入队列并通知设备，不等待完成
+	/// segments 为各数据段的地址和长度，可以为空
+	fn submit(&mut self, v : Request, segments : &[(u64, u32)])->Result<RequestId, IoError> {
+		let queue = self.current_queue();
+		let now = self.now();
+		let (idx, tag) = self.queues[queue].push(v, segments, now)?;
+		self.header.notify(queue as u32);
+		Ok(RequestId { queue, idx, tag })
+	}
+
+	/// 按设备给出的最大扇区数和对齐拆分 discard、write zeroes、secure erase 请求
+	fn range_request(&mut self, blktype : BlockFlag, offset : usize, len : usize,
+			flags : u32, max_sectors : u32, align : u32)->IoResult {
+		if self.read_only() {
+			return Err(IoError::ReadOnly);
+		}
+		self.check_range(offset, len)?;
+		let align = max(align as usize, 1);
+		let mut limit = if max_sectors == 0 {u32::MAX as usize} else {max_sectors as usize};
+		if limit >= align {
+			limit -= limit % align;
+		}
+		let mut sector = offset / SECTOR_SIZE;
+		let end = sector + len / SECTOR_SIZE;
+		while sector < end {
+			// 除第一段外，每段都从对齐处开始
+			let next = min(end, (sector + limit) / align * align);
+			let next = if next <= sector {min(end, sector + limit)} else {next};
+			let segment = RangeSegment {
+				sector : sector as u64,
+				num_sectors : (next - sector) as u32,
+				flags,
+			};
+			let data = &segment as *const RangeSegment as u64;
+			let size = size_of::<RangeSegment>() as u32;
+			let id = self.submit(Request::new(blktype, 0), &[(data, size)])?;
+			// segment 在栈上，必须等待完成
+			self.wait(id)?;
+			sector = next;
+		}
+		Ok(())
+	}
+
+	/// 将多个缓冲区组成请求，每个请求不超过 seg_max 段、每段不超过 size_max
+	fn vectored(&mut self, blktype : BlockFlag, offset : usize, buffers : &[(u64, usize)])->IoResult {
+		let block = self.block_size();
+		let mut total = 0;
+		for &(_, len) in buffers {
+			if len % block != 0 {
+				return Err(IoError::Misaligned);
+			}
+			total += len;
+		}
+		self.check_range(offset, total)?;
+		let max_segments = max(self.max_segments(), 1);
+		let max_len = min(self.max_segment_size(), u32::MAX as usize);
+		let max_len = max(max_len - max_len % block, block);
+		let mut segments = [(0, 0);MAX_SEGMENTS];
+		let mut num = 0;
+		let mut sector = (offset / SECTOR_SIZE) as u64;
+		let mut len = 0;
+		for &(addr, size) in buffers {
+			let mut done = 0;
+			while done < size {
+				let n = min(size - done, max_len);
+				segments[num] = (addr + done as u64, n as u32);
+				num += 1;
+				done += n;
+				len += n;
+				if num == max_segments {
+					let id = self.submit(Request::new(blktype, sector), &segments[..num])?;
+					self.wait(id)?;
+					sector += (len / SECTOR_SIZE) as u64;
+					num = 0;
+					len = 0;
+				}
+			}
+		}
+		if num > 0 {
+			let id = self.submit(Request::new(blktype, sector), &segments[..num])?;
+			self.wait(id)?;
+		}
+		Ok(())
+	}
+
+	/// 安全擦除，擦除后的数据无法通过任何途径恢复
+	pub fn secure_erase(&mut self, offset : usize, len : usize)->IoResult {
+		if !self.has_feature(BlockFeature::SecureErase) {
+			return Err(IoError::Unsupported);
+		}
+		let config = self.config();
+		self.range_request(BlockFlag::SecureErase, offset, len, 0,
+			config.max_secure_erase_sectors, config.secure_erase_sector_alignment)
+	}
+
+	/// 带期限的同步写，超时返回 IoError::Timeout，需要先设置时钟
+	/// 数据按页经 bounce 缓冲区写入，超时时之前的页可能已写入
+	/// 每个队列只有 BOUNCE_PAGES 页 bounce 缓冲区，超时请求占用的页和描述符要等设备归还才回收
+	/// 设备一直不归还时，该队列累计 BOUNCE_PAGES 次超时后带期限请求均返回 IoError::QueueFull
+	pub fn sync_write_until(&mut self, offset : usize, len : usize, data : &[u8], deadline : u64)->IoResult {
+		if self.read_only() {
+			return Err(IoError::ReadOnly);
+		}
+		self.check_until(offset, len, data.len())?;
+		let mut done = 0;
+		while done < len {
+			let n = min(PAGE_SIZE, len - done);
+			let (id, bounce) = self.submit_bounce(BlockFlag::Out, offset + done, n, Some(&data[done..]))?;
+			self.wait_bounce(id, bounce, deadline, None)?;
+			done += n;
+		}
+		Ok(())
+	}
+
+	/// 带期限的同步读，超时返回 IoError::Timeout，需要先设置时钟
+	/// 设备只写入 bounce 缓冲区，成功后才复制到 data，超时后 data 不再被访问
+	/// 每个队列只有 BOUNCE_PAGES 页 bounce 缓冲区，超时请求占用的页和描述符要等设备归还才回收
+	/// 设备一直不归还时，该队列累计 BOUNCE_PAGES 次超时后带期限请求均返回 IoError::QueueFull
+	pub fn sync_read_until(&mut self, offset : usize, len : usize, data : &mut [u8], deadline : u64)->IoResult {
+		self.check_until(offset, len, data.len())?;
+		let mut done = 0;
+		while done < len {
+			let n = min(PAGE_SIZE, len - done);
+			let (id, bounce) = self.submit_bounce(BlockFlag::In, offset + done, n, None)?;
+			self.wait_bounce(id, bounce, deadline, Some(&mut data[done..done + n]))?;
+			done += n;
+		}
+		Ok(())
+	}
+
+	fn check_until(&self, offset : usize, len : usize, buffer : usize)->IoResult {
+		if self.clock.is_none() {
+			return Err(IoError::Unsupported);
+		}
+		if buffer < len {
+			return Err(IoError::BufferTooSmall(len));
+		}
+		self.check_range(offset, len)
+	}
+
+	/// 提交不超过一页、以 bounce 缓冲区为数据段的请求，write 为要写入的数据
+	/// bounce 缓冲区用尽时返回 IoError::QueueFull
+	fn submit_bounce(&mut self, blktype : BlockFlag, offset : usize, len : usize, write : Option<&[u8]>)->Result<(RequestId, usize), IoError> {
+		let queue = self.current_queue();
+		let bounce = self.queues[queue].alloc_bounce().ok_or(IoError::QueueFull)?;
+		let addr = self.queues[queue].bounce_address(bounce);
+		if let Some(data) = write {
+			unsafe {copy_nonoverlapping(data.as_ptr(), addr, len)}
+		}
+		let mut rq = Request::new(blktype, (offset / SECTOR_SIZE) as u64);
+		rq.bounce = Some(bounce);
+		let now = self.now();
+		match self.queues[queue].push(rq, &[(addr as u64, len as u32)], now) {
+			Ok((idx, tag)) => {
+				self.header.notify(queue as u32);
+				Ok((RequestId { queue, idx, tag }, bounce))
+			}
+			Err(err) => {
+				self.queues[queue].free_bounce(bounce);
+				Err(err)
+			}
+		}
+	}
+
+	/// 等待 bounce 请求，成功时把读到的数据复制到 read
+	/// 超时的请求仍占用 bounce 缓冲区，由设备归还时回收
+	fn wait_bounce(&mut self, id : RequestId, bounce : usize, deadline : u64, read : Option<&mut [u8]>)->IoResult {
+		let rt = self.wait_until(id, deadline);
+		if let Err(IoError::Timeout) = rt {
+			return rt;
+		}
+		let queue = &mut self.queues[id.queue];
+		if let (Ok(()), Some(data)) = (&rt, read) {
+			let addr = queue.bounce_address(bounce);
+			unsafe {copy_nonoverlapping(addr, data.as_mut_ptr(), data.len())}
+		}
+		queue.free_bounce(bounce);
+		rt
+	}
+
+	/// 区域化设备的参数，未协商 VIRTIO_BLK_F_ZONED 时为 None
+	pub fn zone_info(&self)->Option<ZoneInfo> {
+		if !self.has_feature(BlockFeature::Zoned) {
+			return None;
+		}
+		let config = self.config();
+		Some(ZoneInfo {
+			model : ZonedModel::from(config.model),
+			zone_sectors : config.zone_sectors,
+			max_open_zones : config.max_open_zones,
+			max_active_zones : config.max_active_zones,
+			max_append_sectors : config.max_append_sectors,
+			write_granularity : config.write_granularity,
+		})
+	}
+
+	/// 从包含 sector 的区域开始报告区域状态，返回填入 zones 的个数
+	pub fn report_zones(&mut self, sector : u64, zones : &mut [Zone])->Result<usize, IoError> {
+		if !self.has_feature(BlockFeature::Zoned) {
+			return Err(IoError::Unsupported);
+		}
+		let capacity = self.capacity() as u64;
+		let zone_sectors = max(self.config().zone_sectors as u64, 1);
+		let mut buffer = [0;PAGE_SIZE];
+		let mut sector = sector;
+		let mut num = 0;
+		while num < zones.len() && sector < capacity {
+			let count = min(zones.len() - num, (PAGE_SIZE - ZONE_REPORT_HEADER) / ZONE_DESC_SIZE);
+			let len = ZONE_REPORT_HEADER + count * ZONE_DESC_SIZE;
+			let data = &mut buffer as *mut [u8] as *mut u8 as u64;
+			let id = self.submit(Request::new(BlockFlag::ZoneReport, sector), &[(data, len as u32)])?;
+			// buffer 在栈上，必须等待完成
+			self.wait(id)?;
+			let reported = min(read_u64(&buffer, 0) as usize, count);
+			if reported == 0 {
+				break;
+			}
+			for desc in buffer[ZONE_REPORT_HEADER..].chunks(ZONE_DESC_SIZE).take(reported) {
+				zones[num] = Zone {
+					capacity : read_u64(desc, 0),
+					start : read_u64(desc, 8),
+					write_pointer : read_u64(desc, 16),
+					ztype : ZoneType::from(desc[24]),
+					state : ZoneState::from(desc[25]),
+				};
+				num += 1;
+			}
+			sector = zones[num - 1].start + zone_sectors;
+		}
+		Ok(num)
+	}
+
+	/// 显式打开 sector 所在区域
+	pub fn zone_open(&mut self, sector : u64)->IoResult {
+		self.zone_command(BlockFlag::ZoneOpen, sector)
+	}
+
+	pub fn zone_close(&mut self, sector : u64)->IoResult {
+		self.zone_command(BlockFlag::ZoneClose, sector)
+	}
+
+	/// 将区域置满，写指针移到区域末尾
+	pub fn zone_finish(&mut self, sector : u64)->IoResult {
+		self.zone_command(BlockFlag::ZoneFinish, sector)
+	}
+
+	/// 清空区域，写指针回到区域起始
+	pub fn zone_reset(&mut self, sector : u64)->IoResult {
+		self.zone_command(BlockFlag::ZoneReset, sector)
+	}
+
+	/// 清空所有顺序写区域
+	pub fn zone_reset_all(&mut self)->IoResult {
+		self.zone_command(BlockFlag::ZoneResetAll, 0)
+	}
+
+	fn zone_command(&mut self, blktype : BlockFlag, sector : u64)->IoResult {
+		if !self.has_feature(BlockFeature::Zoned) {
+			return Err(IoError::Unsupported);
+		}
+		if self.read_only() {
+			return Err(IoError::ReadOnly);
+		}
+		if sector >= self.capacity() as u64 {
+			return Err(IoError::OutOfRange);
+		}
+		let id = self.submit(Request::new(blktype, sector), &[])?;
+		self.wait(id)
+	}
+
+	/// 在 zone 所在区域的写指针处追加数据，返回数据实际写入的起始扇区
+	/// 多个追加请求可以同时发往同一区域，由设备决定先后
+	pub fn zone_append(&mut self, zone : u64, data : &[u8])->Result<u64, IoError> {
+		if !self.has_feature(BlockFeature::Zoned) {
+			return Err(IoError::Unsupported);
+		}
+		if self.read_only() {
+			return Err(IoError::ReadOnly);
+		}
+		let len = data.len();
+		self.check_range(zone as usize * SECTOR_SIZE, len)?;
+		let limit = self.config().max_append_sectors as usize;
+		if limit != 0 && len > limit * SECTOR_SIZE {
+			return Err(IoError::OutOfRange);
+		}
+		let data = data as *const [u8] as *const u8 as u64;
+		let id = self.submit(Request::new(BlockFlag::ZoneAppend, zone), &[(data, len as u32)])?;
+		self.wait(id)?;
+		// 请求槽在下一次提交前不会被复用
+		let rq = self.queues[id.queue].request_pool.get(id.idx);
+		Ok(unsafe {read_volatile(&rq.append_sector)})
+	}
+}
+
+fn read_u64(data : &[u8], offset : usize)->u64 {
+	u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
 }
 
 impl Driver for Block {
     fn handler(&mut self)->InterruptResult {
 		if !self.int.pop() {return Ok(InterruptOk::Block);}
+		if self.header.config_changed() {
+			self.refresh_config();
+		}
 
-		while self.queue.is_pending() {
-			self.mutex.lock();
-			let elem = self.queue.next_elem();
-			let rq = self.queue.desc[elem.id as usize].addr as *mut Request;
-			if unsafe {(*rq).status != 0} {
-				return Err(InterruptError::NoInterrupt);
-			}
-			unsafe {(*rq).lock.unlock()}
-			self.mutex.unlock();
+		let now = self.now();
+		for queue in self.queues.iter_mut() {
+			queue.complete(now);
 		}
 		Ok(InterruptOk::Block)
     }
@@ -134,59 +1007,186 @@ impl Driver for Block {
 		self.int.set_true();
 		Ok(InterruptOk::Block)
     }
+
+	fn set_polling(&mut self, polling : bool) {
+		for queue in self.queues.iter_mut() {
+			queue.polling = polling;
+			queue.queue.set_interrupt(!polling);
+		}
+	}
 }
 
 
 impl BlockDriver for Block {
 	fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
-		self.mutex.lock();
-		let idx = self.queue.desc_idx() as usize;
-		let v = Request::new(data, offset,true);
-		let ori = self.request_pool.get(idx);
-		if ori.status != 0 {
-			return Err(IoError::RequestError);
+		let id = unsafe { self.submit_write(offset, len, data)? };
+		self.wait(id)
+	}
+
+	fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+		let id = unsafe { self.submit_read(offset, len, data)? };
+		self.wait(id)
+	}
+
+	fn flush(&mut self)->IoResult {
+		if !self.has_feature(BlockFeature::Flush) {
+			return Ok(());
+		}
+		let id = self.submit(Request::new(BlockFlag::Flush, 0), &[])?;
+		self.wait(id)
+	}
+
+	fn discard(&mut self, offset : usize, len : usize)->IoResult {
+		if !self.has_feature(BlockFeature::Discard) {
+			return Err(IoError::Unsupported);
+		}
+		let config = self.config();
+		self.range_request(BlockFlag::Discard, offset, len, 0,
+			config.max_discard_sectors, config.discard_sector_alignment)
+	}
+
+	fn write_zeroes(&mut self, offset : usize, len : usize, unmap : bool)->IoResult {
+		if !self.has_feature(BlockFeature::WriteZeroes) {
+			return Err(IoError::Unsupported);
+		}
+		let flags = if unmap {WRITE_ZEROES_FLAG_UNMAP} else {0};
+		let config = self.config();
+		self.range_request(BlockFlag::WriteZeros, offset, len, flags,
+			config.max_write_zeroes_sectors, 1)
+	}
+
+	fn sync_writev(&mut self, offset : usize, data : &[&[u8]])->IoResult {
+		if self.read_only() {
+			return Err(IoError::ReadOnly);
+		}
+		let mut offset = offset;
+		let mut buffers = [(0, 0);MAX_SEGMENTS];
+		for chunk in data.chunks(MAX_SEGMENTS) {
+			let mut len = 0;
+			for (i, buffer) in chunk.iter().enumerate() {
+				buffers[i] = (buffer.as_ptr() as u64, buffer.len());
+				len += buffer.len();
+			}
+			self.vectored(BlockFlag::Out, offset, &buffers[..chunk.len()])?;
+			offset += len;
 		}
-		let rq = self.request_pool.replace_ref(idx, v);
-		let header = &rq.header as *const Header;
-		let status = &rq.status as *const u8;
-		let mut flag = DescFlag::Next as u16;
-		self.queue.add_avail();
-		self.queue.add_desc(header as u64,size_of::<Header>() as u32,flag);
-		self.queue.add_desc(data as *const [u8] as *const u8 as u64, len as u32, flag);
-		flag = DescFlag::Write as u16;
-		self.queue.add_desc(status as u64, 1, flag);
-		self.mutex.unlock();
-		self.header.notify(0);
-		rq.lock.lock();
-		rq.lock.unlock();
 		Ok(())
-		// free(rq as *mut u8);
 	}
 
-	fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
-		self.mutex.lock();
-		let idx = self.queue.desc_idx() as usize;
-		let v = Request::new(data,offset,false);
-		let ori = self.request_pool.get(idx);
-		if ori.status != 0 {
-			return Err(IoError::Info(&"Request pool is full"));
+	fn sync_readv(&mut self, offset : usize, data : &mut [&mut [u8]])->IoResult {
+		let mut offset = offset;
+		let mut buffers = [(0, 0);MAX_SEGMENTS];
+		for chunk in data.chunks_mut(MAX_SEGMENTS) {
+			let mut len = 0;
+			for (i, buffer) in chunk.iter_mut().enumerate() {
+				buffers[i] = (buffer.as_mut_ptr() as u64, buffer.len());
+				len += buffer.len();
+			}
+			self.vectored(BlockFlag::In, offset, &buffers[..chunk.len()])?;
+			offset += len;
 		}
-		let rq = self.request_pool.replace_ref(idx, v);
-		let header = &rq.header as *const Header;
-		let status = &rq.status as *const u8;
-		let mut flag = DescFlag::Next as u16;
-		self.queue.add_avail();
-		self.queue.add_desc(header as u64,size_of::<Header>() as u32,flag);
-		flag |= DescFlag::Write as u16;
-		self.queue.add_desc(data as *const [u8] as *const u8 as u64, len as u32, flag);
-		flag = DescFlag::Write as u16;
-		self.queue.add_desc(status as u64, 1, flag);
-		self.mutex.unlock();
-		rq.lock.lock();
-		self.header.notify(0);
-		rq.lock.lock();
-		rq.lock.unlock();
 		Ok(())
-		// free(rq as *mut u8);
+	}
+
+	fn capacity(&self)->usize {
+		self.capacity
+	}
+
+	fn block_size(&self)->usize {
+		self.block_size
+	}
+
+	fn physical_block_size(&self)->usize {
+		if self.has_feature(BlockFeature::Topology) {
+			self.block_size() << self.config().topology.physical_block_exp
+		}
+		else {
+			self.block_size()
+		}
+	}
+
+	fn read_only(&self)->bool {
+		self.has_feature(BlockFeature::ReadOnly)
+	}
+
+	fn max_segments(&self)->usize {
+		if self.has_feature(BlockFeature::SegMax) {
+			min(self.config().seg_max as usize, MAX_SEGMENTS)
+		}
+		else {
+			1
+		}
+	}
+
+	fn max_segment_size(&self)->usize {
+		if self.has_feature(BlockFeature::SizeMax) {
+			self.config().size_max as usize
+		}
+		else {
+			usize::MAX
+		}
+	}
+}
+
+impl AsyncBlockDriver for Block {
+	unsafe fn submit_write(&mut self, offset : usize, len : usize, data : &[u8])->Result<RequestId, IoError> {
+		if self.read_only() {
+			return Err(IoError::ReadOnly);
+		}
+		if data.len() < len {
+			return Err(IoError::BufferTooSmall(len));
+		}
+		if len > u32::MAX as usize {
+			return Err(IoError::OutOfRange);
+		}
+		self.check_range(offset, len)?;
+		let sector = (offset / SECTOR_SIZE) as u64;
+		let data = data as *const [u8] as *const u8 as u64;
+		self.submit(Request::new(BlockFlag::Out, sector), &[(data, len as u32)])
+	}
+
+	unsafe fn submit_read(&mut self, offset : usize, len : usize, data : &mut [u8])->Result<RequestId, IoError> {
+		if data.len() < len {
+			return Err(IoError::BufferTooSmall(len));
+		}
+		if len > u32::MAX as usize {
+			return Err(IoError::OutOfRange);
+		}
+		self.check_range(offset, len)?;
+		let sector = (offset / SECTOR_SIZE) as u64;
+		let data = data as *mut [u8] as *mut u8 as u64;
+		self.submit(Request::new(BlockFlag::In, sector), &[(data, len as u32)])
+	}
+
+	fn try_wait(&mut self, id : RequestId)->Option<IoResult> {
+		match self.queues.get_mut(id.queue) {
+			Some(queue) => queue.try_wait(id.idx, id.tag),
+			None => Some(Err(IoError::RequestError)),
+		}
+	}
+
+	fn wait(&mut self, id : RequestId)->IoResult {
+		match self.queues.get_mut(id.queue) {
+			Some(queue) => queue.wait(id.idx, id.tag, self.clock),
+			None => Err(IoError::RequestError),
+		}
+	}
+
+	/// 需要先设置时钟
+	fn wait_until(&mut self, id : RequestId, deadline : u64)->IoResult {
+		let clock = match self.clock {
+			Some(clock) => clock,
+			None => return Err(IoError::Unsupported),
+		};
+		match self.queues.get_mut(id.queue) {
+			Some(queue) => queue.wait_until(id.idx, id.tag, clock, deadline),
+			None => Err(IoError::RequestError),
+		}
+	}
+
+	fn cancel(&mut self, id : RequestId) {
+		if let Some(queue) = self.queues.get_mut(id.queue) {
+			queue.cancel(id.idx, id.tag);
+		}
 	}
 }
@@ -0,0 +1,230 @@
+//! # 块缓存
+//! 以页为单位缓存设备数据，写入只修改缓存，被替换或 sync 时才写回设备
+
+use core::{cmp::min, mem::size_of, slice::{from_raw_parts, from_raw_parts_mut}};
+
+use tisu_memory::MemoryOp;
+
+use crate::{InterruptResult, IoResult, config::{IoError, PAGE_SIZE, SECTOR_SIZE}, require::{BlockDriver, Driver}};
+
+/// 缓存块大小，每块占一页
+const CACHE_BLOCK : usize = PAGE_SIZE;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// 缓存的是设备上第几个缓存块
+    block : usize,
+    data : *mut u8,
+    valid : bool,
+    dirty : bool,
+    /// 最近一次访问的时间，用于 LRU 替换
+    used : usize,
+}
+
+pub struct BlockCache<D : BlockDriver> {
+    driver : D,
+    entries : &'static mut [Entry],
+    time : usize,
+}
+
+impl<D : BlockDriver> BlockCache<D> {
+    /// num 为缓存块数量，至少为 1，设备块大小须能整除页大小
+    pub fn new(driver : D, num : usize, memory : &mut impl MemoryOp)->Result<Self, IoError> {
+        if num == 0 {
+            return Err(IoError::Info("block cache needs at least one block"));
+        }
+        let block = driver.block_size();
+        if block > PAGE_SIZE || !PAGE_SIZE.is_multiple_of(block) {
+            return Err(IoError::Unsupported);
+        }
+        let size = (size_of::<Entry>() * num).div_ceil(PAGE_SIZE);
+        let entries = memory.kernel_page(size).ok_or(IoError::NoMemory)? as *mut Entry;
+        let data = match memory.kernel_page(num) {
+            Some(data) => data,
+            None => {
+                memory.free_page(entries as *mut u8);
+                return Err(IoError::NoMemory);
+            }
+        };
+        for i in 0..num {
+            let entry = Entry {
+                block : 0,
+                data : unsafe {data.add(i * CACHE_BLOCK)},
+                valid : false,
+                dirty : false,
+                used : 0,
+            };
+            unsafe {entries.add(i).write(entry)}
+        }
+        Ok(Self {
+            driver,
+            entries : unsafe {from_raw_parts_mut(entries, num)},
+            time : 0,
+        })
+    }
+
+    /// 写回所有脏块后取回设备
+    pub fn into_inner(mut self)->Result<D, IoError> {
+        self.sync()?;
+        Ok(self.driver)
+    }
+
+    /// 将所有脏块写回设备
+    pub fn sync(&mut self)->IoResult {
+        for i in 0..self.entries.len() {
+            self.write_back(i)?;
+        }
+        Ok(())
+    }
+
+    /// 丢弃与范围重叠的缓存块，脏数据不写回
+    fn invalidate(&mut self, offset : usize, len : usize) {
+        let st = offset / CACHE_BLOCK;
+        let ed = (offset + len).div_ceil(CACHE_BLOCK);
+        for entry in self.entries.iter_mut() {
+            if entry.valid && entry.block >= st && entry.block < ed {
+                entry.valid = false;
+                entry.dirty = false;
+            }
+        }
+    }
+
+    /// 缓存块在设备上的有效长度，设备末尾的块可能不满一页
+    fn block_len(&self, block : usize)->usize {
+        let size = self.driver.capacity() * SECTOR_SIZE;
+        min(CACHE_BLOCK, size - block * CACHE_BLOCK)
+    }
+
+    fn write_back(&mut self, idx : usize)->IoResult {
+        let entry = self.entries[idx];
+        if !entry.valid || !entry.dirty {
+            return Ok(());
+        }
+        let len = self.block_len(entry.block);
+        let data = unsafe {from_raw_parts(entry.data, len)};
+        self.driver.sync_write(entry.block * CACHE_BLOCK, len, data)?;
+        self.entries[idx].dirty = false;
+        Ok(())
+    }
+
+    /// 找到或载入缓存块，fill 为假时不从设备读取（调用者将覆盖整块）
+    fn load(&mut self, block : usize, fill : bool)->Result<usize, IoError> {
+        self.time += 1;
+        if let Some(i) = self.entries.iter().position(|e| e.valid && e.block == block) {
+            self.entries[i].used = self.time;
+            return Ok(i);
+        }
+        // 优先使用空闲块，否则替换最久未用的块
+        let mut idx = 0;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let victim = &self.entries[idx];
+            if victim.valid && (!entry.valid || entry.used < victim.used) {
+                idx = i;
+            }
+        }
+        self.write_back(idx)?;
+        self.entries[idx].valid = false;
+        if fill {
+            let len = self.block_len(block);
+            let data = unsafe {from_raw_parts_mut(self.entries[idx].data, len)};
+            self.driver.sync_read(block * CACHE_BLOCK, len, data)?;
+        }
+        let entry = &mut self.entries[idx];
+        entry.block = block;
+        entry.valid = true;
+        entry.dirty = false;
+        entry.used = self.time;
+        Ok(idx)
+    }
+}
+
+impl<D : BlockDriver> Driver for BlockCache<D> {
+    fn handler(&mut self)->InterruptResult {
+        self.driver.handler()
+    }
+
+    fn pending(&mut self)->InterruptResult {
+        self.driver.pending()
+    }
+
+    fn set_polling(&mut self, polling : bool) {
+        self.driver.set_polling(polling)
+    }
+}
+
+impl<D : BlockDriver> BlockDriver for BlockCache<D> {
+    fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
+        if self.read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let block = pos / CACHE_BLOCK;
+            let st = pos % CACHE_BLOCK;
+            let n = min(len - done, self.block_len(block) - st);
+            let idx = self.load(block, n != self.block_len(block))?;
+            let entry = &mut self.entries[idx];
+            unsafe {entry.data.add(st).copy_from(data[done..].as_ptr(), n)}
+            entry.dirty = true;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let block = pos / CACHE_BLOCK;
+            let st = pos % CACHE_BLOCK;
+            let n = min(len - done, self.block_len(block) - st);
+            let idx = self.load(block, true)?;
+            let entry = &self.entries[idx];
+            unsafe {data[done..].as_mut_ptr().copy_from(entry.data.add(st), n)}
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self)->IoResult {
+        self.sync()?;
+        self.driver.flush()
+    }
+
+    fn discard(&mut self, offset : usize, len : usize)->IoResult {
+        self.sync()?;
+        self.invalidate(offset, len);
+        self.driver.discard(offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset : usize, len : usize, unmap : bool)->IoResult {
+        self.sync()?;
+        self.invalidate(offset, len);
+        self.driver.write_zeroes(offset, len, unmap)
+    }
+
+    fn capacity(&self)->usize {
+        self.driver.capacity()
+    }
+
+    fn block_size(&self)->usize {
+        self.driver.block_size()
+    }
+
+    fn physical_block_size(&self)->usize {
+        self.driver.physical_block_size()
+    }
+
+    fn read_only(&self)->bool {
+        self.driver.read_only()
+    }
+}
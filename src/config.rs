@@ -6,6 +6,8 @@
 
 use crate::input::{InputEvent};
 pub const PAGE_SIZE : usize = 4096;
+/// 块设备扇区大小，virtio-blk 的 sector 字段总以此为单位
+pub const SECTOR_SIZE : usize = 512;
 
 #[derive(Clone, Copy, Default, Debug)]
 pub struct Rect {
@@ -108,9 +110,168 @@ pub enum SetupError {
 #[derive(Debug)]
 pub enum IoError {
     RequestError,
+    /// 请求队列已满，需等待已有请求完成
+    QueueFull,
+    /// 请求超出设备容量
+    OutOfRange,
+    /// 偏移或长度未按块大小对齐
+    Misaligned,
+    /// 缓冲区小于请求长度
+    BufferTooSmall(usize),
+    /// 设备只读，不接受修改数据的请求
+    ReadOnly,
+    /// 设备不支持此操作
+    Unsupported,
+    /// 设备报告读写出错
+    DeviceError,
+    /// 磁盘上的数据校验失败
+    Corrupted,
+    /// 磁盘没有可识别的分区表
+    NoPartitionTable,
+    /// 区域命令不适用于目标区域
+    ZoneInvalid,
+    /// 写入位置不在顺序写区域的写指针处
+    ZoneUnalignedWrite,
+    /// 打开的区域数已达上限
+    ZoneOpenResource,
+    /// 活跃的区域数已达上限
+    ZoneActiveResource,
+    /// 请求在期限内未完成，已被放弃
+    Timeout,
+    /// 内核无法分配所需的内存
+    NoMemory,
+    /// 密钥长度不合法，或 XTS 的两把密钥相同
+    InvalidKey,
     Info(&'static str),
 }
 
+/// 异步请求句柄，请求完成并取回结果后失效
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequestId {
+    pub(crate) queue : usize,
+    pub(crate) idx : usize,
+    pub(crate) tag : usize,
+}
+
+/// 块设备写缓存模式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheMode {
+    /// 写入先进缓存，需要 flush 才能保证落盘
+    WriteBack,
+    /// 写入完成即落盘
+    WriteThrough,
+}
+
+/// 区域化设备模型
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZonedModel {
+    /// 不分区域
+    None,
+    /// 主机管理，顺序写区域只能在写指针处写入
+    HostManaged,
+    /// 主机感知，也可当作普通设备使用
+    HostAware,
+}
+
+impl ZonedModel {
+    pub fn from(num : u8)->Self {
+        match num {
+            1 => ZonedModel::HostManaged,
+            2 => ZonedModel::HostAware,
+            _ => ZonedModel::None,
+        }
+    }
+}
+
+/// 区域化设备参数，除 write_granularity 外单位均为扇区（512 字节）
+#[derive(Clone, Copy, Debug)]
+pub struct ZoneInfo {
+    pub model : ZonedModel,
+    pub zone_sectors : u32,
+    /// 0 表示没有限制
+    pub max_open_zones : u32,
+    /// 0 表示没有限制
+    pub max_active_zones : u32,
+    pub max_append_sectors : u32,
+    /// 写入的最小单位（字节）
+    pub write_granularity : u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZoneType {
+    /// 可随机写
+    Conventional,
+    /// 只能在写指针处顺序写
+    SeqWriteRequired,
+    /// 建议顺序写
+    SeqWritePreferred,
+    Unknown(u8),
+}
+
+impl ZoneType {
+    pub fn from(num : u8)->Self {
+        match num {
+            1 => ZoneType::Conventional,
+            2 => ZoneType::SeqWriteRequired,
+            3 => ZoneType::SeqWritePreferred,
+            _ => ZoneType::Unknown(num),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZoneState {
+    /// 常规区域没有写指针
+    NotWritePointer,
+    Empty,
+    ImplicitOpen,
+    ExplicitOpen,
+    Closed,
+    ReadOnly,
+    Full,
+    Offline,
+    Unknown(u8),
+}
+
+impl ZoneState {
+    pub fn from(num : u8)->Self {
+        match num {
+            0 => ZoneState::NotWritePointer,
+            1 => ZoneState::Empty,
+            2 => ZoneState::ImplicitOpen,
+            3 => ZoneState::ExplicitOpen,
+            4 => ZoneState::Closed,
+            13 => ZoneState::ReadOnly,
+            14 => ZoneState::Full,
+            15 => ZoneState::Offline,
+            _ => ZoneState::Unknown(num),
+        }
+    }
+}
+
+/// 区域描述，单位均为扇区（512 字节）
+#[derive(Clone, Copy, Debug)]
+pub struct Zone {
+    pub start : u64,
+    /// 可写入的扇区数，不超过区域大小
+    pub capacity : u64,
+    pub write_pointer : u64,
+    pub ztype : ZoneType,
+    pub state : ZoneState,
+}
+
+impl Default for Zone {
+    fn default() -> Self {
+        Self {
+            start : 0,
+            capacity : 0,
+            write_pointer : 0,
+            ztype : ZoneType::Conventional,
+            state : ZoneState::NotWritePointer,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum InterruptOk {
     Null,
@@ -132,6 +293,16 @@ pub enum InputType {
     Status(InputEvent),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetError {
+    /// 缓冲区小于帧长度
+    BufferTooSmall(usize),
+    /// 发送队列已满，需等待设备发送完已有的帧
+    QueueFull,
+    /// 帧超过发送缓冲区大小
+    FrameTooLarge,
+}
+
 #[derive(Debug)]
 pub enum GraphicError {
     InvalidRect(Rect),
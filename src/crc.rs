@@ -0,0 +1,74 @@
+//! # CRC32 校验
+//! 查表实现，表在编译期生成，支持 IEEE 与 Castagnoli（CRC32C）两种多项式
+
+/// IEEE 802.3 多项式（反射），GPT 使用
+const POLY_IEEE : u32 = 0xEDB8_8320;
+/// Castagnoli 多项式（反射），检错能力更好，用于扇区校验
+const POLY_CASTAGNOLI : u32 = 0x82F6_3B78;
+
+const fn make_table(poly : u32)->[u32;256] {
+    let mut table = [0;256];
+    let mut i = 0;
+    while i < 256 {
+        let mut v = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            v = if v & 1 != 0 {(v >> 1) ^ poly} else {v >> 1};
+            j += 1;
+        }
+        table[i] = v;
+        i += 1;
+    }
+    table
+}
+
+static TABLE_IEEE : [u32;256] = make_table(POLY_IEEE);
+static TABLE_CASTAGNOLI : [u32;256] = make_table(POLY_CASTAGNOLI);
+
+/// 可分段计算的 CRC32
+pub struct Crc32 {
+    table : &'static [u32;256],
+    value : u32,
+}
+
+impl Crc32 {
+    pub fn new()->Self {
+        Self {
+            table : &TABLE_IEEE,
+            value : !0,
+        }
+    }
+
+    pub fn castagnoli()->Self {
+        Self {
+            table : &TABLE_CASTAGNOLI,
+            value : !0,
+        }
+    }
+
+    pub fn update(&mut self, data : &[u8]) {
+        for &b in data {
+            self.value = self.table[((self.value ^ b as u32) & 0xff) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    pub fn finish(&self)->u32 {
+        !self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Crc32;
+
+    #[test]
+    fn check_values() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+        let mut crc = Crc32::castagnoli();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xE306_9283);
+    }
+}
@@ -0,0 +1,239 @@
+//! # 块设备加密
+//! 以扇区为单位做 AES-XTS 加密，扇区号作为 tweak，上层看到的是明文
+//! 密钥在构造时给定，此后不再变化，丢弃时清零
+
+use core::{cmp::min, convert::TryInto, mem::ManuallyDrop, ptr::read};
+
+use crate::{InterruptResult, IoResult, aes::Aes, config::{IoError, PAGE_SIZE, SECTOR_SIZE}, require::{BlockDriver, Driver}};
+
+const BLOCK : usize = 16;
+
+pub struct Encrypted<D : BlockDriver> {
+    driver : D,
+    /// 加密数据的密钥
+    data : Aes,
+    /// 加密 tweak 的密钥
+    tweak : Aes,
+}
+
+impl<D : BlockDriver> Encrypted<D> {
+    /// key 为两把等长 AES 密钥拼接而成，32 字节为 AES-128-XTS，64 字节为 AES-256-XTS
+    /// IEEE 1619-2018 要求两把密钥不同
+    pub fn new(driver : D, key : &[u8])->Result<Self, IoError> {
+        if key.len() != 32 && key.len() != 64 {
+            return Err(IoError::InvalidKey);
+        }
+        let (data, tweak) = key.split_at(key.len() / 2);
+        if data == tweak {
+            return Err(IoError::InvalidKey);
+        }
+        Ok(Self {
+            driver,
+            data : Aes::new(data).unwrap(),
+            tweak : Aes::new(tweak).unwrap(),
+        })
+    }
+
+    /// 清零密钥后交还底层设备
+    pub fn into_inner(self)->D {
+        let mut this = ManuallyDrop::new(self);
+        this.wipe();
+        unsafe {read(&this.driver)}
+    }
+
+    fn wipe(&mut self) {
+        self.data.wipe();
+        self.tweak.wipe();
+    }
+
+    /// 对整数个扇区原地加解密，sector 为第一个扇区的扇区号
+    fn xts(&self, sector : usize, data : &mut [u8], encrypt : bool) {
+        for (i, unit) in data.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            let mut t = [0;BLOCK];
+            t[..8].copy_from_slice(&((sector + i) as u64).to_le_bytes());
+            self.tweak.encrypt(&mut t);
+            for block in unit.chunks_exact_mut(BLOCK) {
+                let block : &mut [u8;BLOCK] = block.try_into().unwrap();
+                xor(block, &t);
+                if encrypt {
+                    self.data.encrypt(block);
+                }
+                else {
+                    self.data.decrypt(block);
+                }
+                xor(block, &t);
+                next_tweak(&mut t);
+            }
+        }
+    }
+
+    /// data 为空时写入加密后的 0
+    fn write_encrypted(&mut self, offset : usize, len : usize, data : Option<&[u8]>)->IoResult {
+        let mut buffer = [0;PAGE_SIZE];
+        let mut done = 0;
+        while done < len {
+            let n = min(PAGE_SIZE, len - done);
+            let buffer = &mut buffer[..n];
+            match data {
+                Some(data) => buffer.copy_from_slice(&data[done..done + n]),
+                None => buffer.iter_mut().for_each(|b| *b = 0),
+            }
+            self.xts((offset + done) / SECTOR_SIZE, buffer, true);
+            self.driver.sync_write(offset + done, n, buffer)?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+fn xor(block : &mut [u8;BLOCK], t : &[u8;BLOCK]) {
+    for (b, t) in block.iter_mut().zip(t.iter()) {
+        *b ^= t;
+    }
+}
+
+/// tweak 在 GF(2^128) 上乘以 x，小端序
+fn next_tweak(t : &mut [u8;BLOCK]) {
+    let mut carry = 0;
+    for b in t.iter_mut() {
+        let next = *b >> 7;
+        *b = (*b << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        t[0] ^= 0x87;
+    }
+}
+
+impl<D : BlockDriver> Driver for Encrypted<D> {
+    fn handler(&mut self)->InterruptResult {
+        self.driver.handler()
+    }
+
+    fn pending(&mut self)->InterruptResult {
+        self.driver.pending()
+    }
+
+    fn set_polling(&mut self, polling : bool) {
+        self.driver.set_polling(polling)
+    }
+}
+
+impl<D : BlockDriver> BlockDriver for Encrypted<D> {
+    /// 明文先复制到栈上加密，调用者的缓冲区不被修改
+    fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
+        if self.read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        self.write_encrypted(offset, len, Some(data))
+    }
+
+    fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        self.driver.sync_read(offset, len, data)?;
+        self.xts(offset / SECTOR_SIZE, &mut data[..len], false);
+        Ok(())
+    }
+
+    fn flush(&mut self)->IoResult {
+        self.driver.flush()
+    }
+
+    /// 被丢弃的数据解密后是不确定的内容，而非 0
+    fn discard(&mut self, offset : usize, len : usize)->IoResult {
+        self.driver.discard(offset, len)
+    }
+
+    /// 设备写入的 0 解密后不是 0，只能逐扇区写入密文
+    fn write_zeroes(&mut self, offset : usize, len : usize, _unmap : bool)->IoResult {
+        if self.read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        self.check_range(offset, len)?;
+        self.write_encrypted(offset, len, None)
+    }
+
+    fn capacity(&self)->usize {
+        self.driver.capacity()
+    }
+
+    fn block_size(&self)->usize {
+        self.driver.block_size()
+    }
+
+    fn physical_block_size(&self)->usize {
+        self.driver.physical_block_size()
+    }
+
+    fn read_only(&self)->bool {
+        self.driver.read_only()
+    }
+}
+
+impl<D : BlockDriver> Drop for Encrypted<D> {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::ramdisk::tests::disk;
+
+    /// IEEE 1619-2007 附录 B 向量 4，数据单元序号为 0
+    const KEY : &str = "2718281828459045235360287471352631415926535897932384626433832795";
+    const CIPHER : [&str;16] = [
+        "27a7479befa1d476489f308cd4cfa6e2a96e4bbe3208ff25287dd3819616e89c",
+        "c78cf7f5e543445f8333d8fa7f56000005279fa5d8b5e4ad40e736ddb4d35412",
+        "328063fd2aab53e5ea1e0a9f332500a5df9487d07a5c92cc512c8866c7e860ce",
+        "93fdf166a24912b422976146ae20ce846bb7dc9ba94a767aaef20c0d61ad0265",
+        "5ea92dc4c4e41a8952c651d33174be51a10c421110e6d81588ede82103a252d8",
+        "a750e8768defffed9122810aaeb99f9172af82b604dc4b8e51bcb08235a6f434",
+        "1332e4ca60482a4ba1a03b3e65008fc5da76b70bf1690db4eae29c5f1badd03c",
+        "5ccf2a55d705ddcd86d449511ceb7ec30bf12b1fa35b913f9f747a8afd1b130e",
+        "94bff94effd01a91735ca1726acd0b197c4e5b03393697e126826fb6bbde8ecc",
+        "1e08298516e2c9ed03ff3c1b7860f6de76d4cecd94c8119855ef5297ca67e9f3",
+        "e7ff72b1e99785ca0a7e7720c5b36dc6d72cac9574c8cbbc2f801e23e56fd344",
+        "b07f22154beba0f08ce8891e643ed995c94d9a69c9f1b5f499027a78572aeebd",
+        "74d20cc39881c213ee770b1010e4bea718846977ae119f7a023ab58cca0ad752",
+        "afe656bb3c17256a9f6e9bf19fdd5a38fc82bbe872c5539edb609ef4f79c203e",
+        "bb140f2e583cb2ad15b4aa5b655016a8449277dbd477ef2c8d6c017db738b18d",
+        "eb4a427d1923ce3ff262735779a418f20a282df920147beabe421ee5319d0568",
+    ];
+
+    fn hex(s : &str)->Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn xts_vector() {
+        let e = Encrypted::new(disk(4), &hex(KEY)).unwrap();
+        let plain : Vec<u8> = (0..SECTOR_SIZE).map(|i| i as u8).collect();
+        let mut data = plain.clone();
+        e.xts(0, &mut data, true);
+        assert_eq!(data, hex(&CIPHER.concat()));
+        e.xts(0, &mut data, false);
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn reject_keys() {
+        assert!(matches!(Encrypted::new(disk(4), &[0;48]), Err(IoError::InvalidKey)));
+        let mut key = [7;64];
+        assert!(matches!(Encrypted::new(disk(4), &key), Err(IoError::InvalidKey)));
+        key[63] = 8;
+        assert!(Encrypted::new(disk(4), &key).is_ok());
+    }
+}
@@ -26,6 +26,8 @@ pub struct GPU{
     height : usize,
     mutex : SpinMutex,
     int : Bool,
+    /// 轮询模式，提交命令后等待设备处理完
+    polling : bool,
 }
 
 impl GPU {
@@ -60,6 +62,7 @@ impl GPU {
             height,
             mutex: SpinMutex::new(),
             int: Bool::new(),
+            polling: false,
         };
         rt.reset();
         rt
@@ -110,10 +113,10 @@ impl GPU {
         let idx = self.queue.desc_idx() as usize;
         let addr = self.header_pool.replace_u64(idx, header);
         let ref mut q = self.queue;
-        q.add_avail();
         q.add_desc(addr1, size_of::<T>() as u32, DescFlag::Next as u16);
         q.add_desc(addr, size_of::<ControllHeader>() as u32,
         DescFlag::Write as u16);
+        q.add_avail(idx as u16);
     }
 
     /// 将 source 与某块内存绑定
@@ -123,9 +126,8 @@ impl GPU {
             (self.width * self.height * size_of::<Pixel>()) as u32);
         let ctype = ControllType::ResourceAttachBacking;
         let header = ControllHeader::default_val(ctype);
-        let idx = self.queue.desc_idx() as usize;
-        let addr1 = self.attach_pool.replace_u64(idx, at);
-        self.queue.add_avail();
+        let head = self.queue.desc_idx();
+        let addr1 = self.attach_pool.replace_u64(head as usize, at);
         self.queue.add_desc(addr1, size_of::<AttachBacking>() as u32,
         DescFlag::Next as u16);
         let idx = self.queue.desc_idx() as usize;
@@ -136,11 +138,17 @@ impl GPU {
         let addr = self.header_pool.replace_u64(idx, header);
         self.queue.add_desc(addr, size_of::<ControllHeader>() as u32,
         DescFlag::Write as u16);
+        self.queue.add_avail(head);
     }
 
-    /// 发送 QueueNotify
+    /// 发送 QueueNotify，轮询模式下等待设备处理完所有命令
     fn run(&mut self){
         self.header.notify(0);
+        if self.polling {
+            while !self.queue.is_idle() {
+                let _ = self.poll();
+            }
+        }
     }
 
     fn fill_rect(&mut self, x : usize, y : usize, width : usize, height : usize, color : Pixel){
@@ -177,6 +185,11 @@ impl Driver for GPU {
         self.int.set_true();
         Ok(InterruptOk::Graphic)
     }
+
+    fn set_polling(&mut self, polling : bool) {
+        self.polling = polling;
+        self.queue.set_interrupt(!polling);
+    }
 }
 
 impl GraphicDriver for GPU {
@@ -2,8 +2,13 @@
 //! 
 //! 2021年3月28日 zg
 
+use core::ptr::{read_volatile, write_volatile};
+
 use crate::config::SetupError;
 
+/// 中断状态中表示配置空间发生变化的位
+const VIRTIO_MMIO_INT_CONFIG : u32 = 2;
+
 
 #[allow(dead_code)]
 pub enum StatusField {
@@ -62,7 +67,8 @@ pub struct VirtHeader {
 }
 
 impl VirtHeader {
-    pub fn set_feature(&mut self, guest_feat : u32)->Result<(), SetupError> {
+    /// 协商特性，返回双方都支持的特性位
+    pub fn set_feature(&mut self, guest_feat : u32)->Result<u32, SetupError> {
         self.status = 0;
         self.status = StatusField::Acknowledge.val32();
         self.status |= StatusField::DriverOk.val32();
@@ -72,7 +78,7 @@ impl VirtHeader {
         if status_ok & StatusField::FeaturesOk.val32() == 0 {
             return Err(SetupError::FeatureFail);
         }
-        Ok(())
+        Ok(self.host_features & guest_feat)
     }
 
     pub fn set_ring_size(&mut self, size : u32)->Result<(), SetupError> {
@@ -85,6 +91,14 @@ impl VirtHeader {
         }
     }
 
+    /// 选中队列后设置其大小和物理页号，多队列设备需要逐个设置
+    pub fn set_queue(&mut self, sel : u32, size : u32, pfn : u32)->Result<(), SetupError> {
+        self.queue_sel = sel;
+        self.set_ring_size(size)?;
+        self.queue_pfn = pfn;
+        Ok(())
+    }
+
     pub fn set_pfn(&mut self, sel : u32, pfn : u32) {
         self.queue_sel = sel;
         self.queue_pfn = pfn;
@@ -102,6 +116,20 @@ impl VirtHeader {
         self.queue_notify = idx;
     }
 
+    /// 设备是否报告配置空间发生了变化，是则应答该中断
+    pub fn config_changed(&mut self)->bool {
+        if unsafe {read_volatile(&self.interrupt_status)} & VIRTIO_MMIO_INT_CONFIG == 0 {
+            return false;
+        }
+        unsafe {write_volatile(&mut self.interrupt_ack, VIRTIO_MMIO_INT_CONFIG)}
+        true
+    }
+
+    /// 配置空间的版本号，读取配置前后不一致说明期间被设备修改，legacy 设备恒为 0
+    pub fn config_generation(&self)->u32 {
+        unsafe {read_volatile(&self.config_generation)}
+    }
+
     pub fn config_address(&self)->usize {
         self as *const Self as *const u8 as usize + 0x100
     }
@@ -142,8 +142,20 @@ impl InputDevice {
     fn fill_event(&mut self, buffer_idx : usize) {
         let addr = unsafe {self.buffer.add(buffer_idx % EVENT_BUFFER_SIZE) as u64};
         let size = size_of::<InputEvent>() as u32;
-        self.event_queue.add_avail();
+        let head = self.event_queue.desc_idx();
         self.event_queue.add_desc(addr, size, DescFlag::Write as u16);
+        self.event_queue.add_avail(head);
+    }
+
+    /// 取出一个 status 事件
+    fn status_event(&mut self)->Option<InputEvent> {
+        if !self.status_queue.is_pending() {
+            return None;
+        }
+        let ref elem = self.status_queue.next_elem();
+        let ref desc = self.status_queue.desc[elem.id as usize];
+        let event = unsafe {(desc.addr as *const InputEvent).as_ref().unwrap()};
+        Some(*event)
     }
 }
 
@@ -166,19 +178,32 @@ impl Driver for InputDevice {
         rt
     }
 
+    /// 事件本就由 handler 从队列中取出，轮询时只需关闭中断
+    fn set_polling(&mut self, polling : bool) {
+        self.event_queue.set_interrupt(!polling);
+        self.status_queue.set_interrupt(!polling);
+    }
+
     /// 此函数仅处理 status 事件
     fn pending(&mut self)->InterruptResult {
-        let mut rt = Err(InterruptError::NoInterrupt);
         if self.event_queue.is_pending() {
-            rt = Ok(InterruptOk::Null);
+            return Ok(InterruptOk::Null);
         }
-        else if self.status_queue.is_pending() {
-            let ref elem = self.status_queue.next_elem();
-            let ref desc = self.status_queue.desc[elem.id as usize];
-            let event = unsafe {(desc.addr as *const InputEvent).as_ref().unwrap()};
-            rt = Ok(InterruptOk::Input(*event));
+        match self.status_event() {
+            Some(event) => Ok(InterruptOk::Input(event)),
+            None => Err(InterruptError::NoInterrupt),
+        }
+    }
+
+    /// 先取事件队列，再取 status 队列，都没有事件时返回 Null
+    fn poll(&mut self)->InterruptResult {
+        match self.handler()? {
+            InterruptOk::Null => match self.status_event() {
+                Some(event) => Ok(InterruptOk::Input(event)),
+                None => Ok(InterruptOk::Null),
+            },
+            rt => Ok(rt),
         }
-        rt
     }
 }
 
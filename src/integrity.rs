@@ -0,0 +1,271 @@
+//! # 校验块设备
+//! 每个逻辑块在设备末尾的校验区中保存一个 CRC32C，读取时校验，不符则报告数据损坏
+//! 校验和包含块号，写错位置的数据同样能被发现
+//! 先写数据再写校验和，两者之间断电会使该块在下次读取时报告损坏
+
+use core::convert::TryInto;
+
+use crate::{InterruptResult, IoResult, config::{IoError, PAGE_SIZE, SECTOR_SIZE}, crc::Crc32, require::{BlockDriver, Driver}};
+
+const SUM_SIZE : usize = 4;
+
+pub struct Integrity<D : BlockDriver> {
+    driver : D,
+    block : usize,
+    /// 数据块数，校验区紧随其后
+    blocks : usize,
+    /// 缓存的校验区块
+    meta : [u8;PAGE_SIZE],
+    meta_idx : Option<usize>,
+    dirty : bool,
+}
+
+impl<D : BlockDriver> Integrity<D> {
+    /// 首次使用或设备内容被绕过本层修改后，须调用 rebuild 生成校验和
+    pub fn new(driver : D)->Result<Self, IoError> {
+        let block = driver.block_size();
+        if block > PAGE_SIZE {
+            return Err(IoError::Unsupported);
+        }
+        let total = driver.capacity() * SECTOR_SIZE / block;
+        let per = block / SUM_SIZE;
+        let blocks = total * per / (per + 1);
+        if blocks == 0 {
+            return Err(IoError::OutOfRange);
+        }
+        Ok(Self {
+            driver,
+            block,
+            blocks,
+            meta : [0;PAGE_SIZE],
+            meta_idx : None,
+            dirty : false,
+        })
+    }
+
+    pub fn into_inner(self)->D {
+        self.driver
+    }
+
+    /// 按设备现有内容重新计算所有校验和
+    pub fn rebuild(&mut self)->IoResult {
+        let block = self.block;
+        let mut buffer = [0;PAGE_SIZE];
+        for idx in 0..self.blocks {
+            self.driver.sync_read(idx * block, block, &mut buffer[..block])?;
+            let sum = self.checksum(idx, &buffer[..block]);
+            self.set_sum(idx, sum)?;
+        }
+        self.store_meta()
+    }
+
+    fn checksum(&self, idx : usize, data : &[u8])->u32 {
+        let mut crc = Crc32::castagnoli();
+        crc.update(&(idx as u64).to_le_bytes());
+        crc.update(&data[..self.block]);
+        crc.finish()
+    }
+
+    /// 载入第 m 个校验区块，换出前先写回
+    fn load_meta(&mut self, m : usize)->IoResult {
+        if self.meta_idx == Some(m) {
+            return Ok(());
+        }
+        self.store_meta()?;
+        self.meta_idx = None;
+        let block = self.block;
+        self.driver.sync_read((self.blocks + m) * block, block, &mut self.meta[..block])?;
+        self.meta_idx = Some(m);
+        Ok(())
+    }
+
+    fn store_meta(&mut self)->IoResult {
+        if let Some(m) = self.meta_idx {
+            if self.dirty {
+                let block = self.block;
+                self.driver.sync_write((self.blocks + m) * block, block, &self.meta[..block])?;
+                self.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn sum(&mut self, idx : usize)->Result<u32, IoError> {
+        let per = self.block / SUM_SIZE;
+        self.load_meta(idx / per)?;
+        let st = idx % per * SUM_SIZE;
+        Ok(u32::from_le_bytes(self.meta[st..st + SUM_SIZE].try_into().unwrap()))
+    }
+
+    fn set_sum(&mut self, idx : usize, sum : u32)->IoResult {
+        let per = self.block / SUM_SIZE;
+        self.load_meta(idx / per)?;
+        let st = idx % per * SUM_SIZE;
+        self.meta[st..st + SUM_SIZE].copy_from_slice(&sum.to_le_bytes());
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+impl<D : BlockDriver> Driver for Integrity<D> {
+    fn handler(&mut self)->InterruptResult {
+        self.driver.handler()
+    }
+
+    fn pending(&mut self)->InterruptResult {
+        self.driver.pending()
+    }
+
+    fn set_polling(&mut self, polling : bool) {
+        self.driver.set_polling(polling)
+    }
+}
+
+impl<D : BlockDriver> BlockDriver for Integrity<D> {
+    fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        self.driver.sync_write(offset, len, data)?;
+        let block = self.block;
+        for (i, data) in data[..len].chunks(block).enumerate() {
+            let idx = offset / block + i;
+            let sum = self.checksum(idx, data);
+            self.set_sum(idx, sum)?;
+        }
+        self.store_meta()
+    }
+
+    fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        self.driver.sync_read(offset, len, data)?;
+        let block = self.block;
+        for (i, data) in data[..len].chunks(block).enumerate() {
+            let idx = offset / block + i;
+            if self.checksum(idx, data) != self.sum(idx)? {
+                return Err(IoError::Corrupted);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self)->IoResult {
+        self.store_meta()?;
+        self.driver.flush()
+    }
+
+    /// 被丢弃的块须有确定的内容才能校验，因此以写 0 代替
+    fn discard(&mut self, offset : usize, len : usize)->IoResult {
+        self.write_zeroes(offset, len, true)
+    }
+
+    fn write_zeroes(&mut self, offset : usize, len : usize, unmap : bool)->IoResult {
+        self.check_range(offset, len)?;
+        self.driver.fill_zeroes(offset, len, unmap)?;
+        let block = self.block;
+        let zero = [0;PAGE_SIZE];
+        for idx in offset / block..(offset + len) / block {
+            let sum = self.checksum(idx, &zero);
+            self.set_sum(idx, sum)?;
+        }
+        self.store_meta()
+    }
+
+    /// 不含校验区
+    fn capacity(&self)->usize {
+        self.blocks * self.block / SECTOR_SIZE
+    }
+
+    fn block_size(&self)->usize {
+        self.block
+    }
+
+    fn physical_block_size(&self)->usize {
+        self.driver.physical_block_size()
+    }
+
+    fn read_only(&self)->bool {
+        self.driver.read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RamDisk, ramdisk::tests::disk};
+
+    const SECTORS : usize = 16;
+
+    fn integrity()->Integrity<RamDisk> {
+        let mut integrity = Integrity::new(disk(SECTORS)).unwrap();
+        integrity.rebuild().unwrap();
+        integrity
+    }
+
+    fn fill(idx : usize)->[u8;SECTOR_SIZE] {
+        let mut data = [0;SECTOR_SIZE];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i + idx) as u8;
+        }
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut integrity = integrity();
+        assert_eq!(integrity.capacity(), 15);
+        for idx in 0..4 {
+            integrity.sync_write(idx * SECTOR_SIZE, SECTOR_SIZE, &fill(idx)).unwrap();
+        }
+        let mut data = [0;4 * SECTOR_SIZE];
+        integrity.sync_read(0, 4 * SECTOR_SIZE, &mut data).unwrap();
+        for idx in 0..4 {
+            assert_eq!(data[idx * SECTOR_SIZE..(idx + 1) * SECTOR_SIZE], fill(idx));
+        }
+        integrity.write_zeroes(SECTOR_SIZE, SECTOR_SIZE, false).unwrap();
+        integrity.sync_read(SECTOR_SIZE, SECTOR_SIZE, &mut data).unwrap();
+        assert!(data[..SECTOR_SIZE].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let mut integrity = integrity();
+        integrity.sync_write(2 * SECTOR_SIZE, SECTOR_SIZE, &fill(2)).unwrap();
+        let mut data = fill(2);
+        data[100] ^= 1;
+        integrity.driver.sync_write(2 * SECTOR_SIZE, SECTOR_SIZE, &data).unwrap();
+        assert!(matches!(integrity.sync_read(2 * SECTOR_SIZE, SECTOR_SIZE, &mut data), Err(IoError::Corrupted)));
+    }
+
+    #[test]
+    fn misplaced_block() {
+        let mut integrity = integrity();
+        integrity.sync_write(SECTOR_SIZE, SECTOR_SIZE, &fill(1)).unwrap();
+        integrity.sync_write(3 * SECTOR_SIZE, SECTOR_SIZE, &fill(3)).unwrap();
+        let mut data = [0;SECTOR_SIZE];
+        integrity.driver.sync_read(SECTOR_SIZE, SECTOR_SIZE, &mut data).unwrap();
+        integrity.driver.sync_write(3 * SECTOR_SIZE, SECTOR_SIZE, &data).unwrap();
+        assert!(matches!(integrity.sync_read(3 * SECTOR_SIZE, SECTOR_SIZE, &mut data), Err(IoError::Corrupted)));
+        integrity.sync_read(SECTOR_SIZE, SECTOR_SIZE, &mut data).unwrap();
+    }
+
+    #[test]
+    fn rebuild() {
+        let mut disk = disk(SECTORS);
+        for idx in 0..4 {
+            disk.sync_write(idx * SECTOR_SIZE, SECTOR_SIZE, &fill(idx)).unwrap();
+        }
+        let mut integrity = Integrity::new(disk).unwrap();
+        let mut data = [0;SECTOR_SIZE];
+        assert!(matches!(integrity.sync_read(0, SECTOR_SIZE, &mut data), Err(IoError::Corrupted)));
+        integrity.rebuild().unwrap();
+        for idx in 0..4 {
+            integrity.sync_read(idx * SECTOR_SIZE, SECTOR_SIZE, &mut data).unwrap();
+            assert_eq!(data, fill(idx));
+        }
+    }
+}
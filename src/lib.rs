@@ -8,21 +8,44 @@ mod gpu;
 mod input;
 mod pool;
 mod net;
+mod sched;
+mod cache;
+mod crc;
+mod partition;
+mod ramdisk;
+mod overlay;
+mod aes;
+mod crypt;
+mod integrity;
+#[cfg(feature = "smoltcp")]
+mod phy;
 
-use config::{GraphicError, IoError};
-pub use config::{InterruptError, InterruptOk, DeviceType};
+use config::GraphicError;
+pub use config::{InterruptError, InterruptOk, DeviceType, IoError, NetError, RequestId, CacheMode};
+pub use config::{Zone, ZoneInfo, ZoneState, ZoneType, ZonedModel};
 pub use header::VirtHeader;
 pub use queue::VirtQueue;
-pub use block::Block;
+pub use block::{Block, BlockStats, LATENCY_BUCKETS, SERIAL_LEN};
 pub use gpu::GPU;
 pub use net::Net;
+#[cfg(feature = "smoltcp")]
+pub use phy::{NetRxToken, NetTxToken};
+pub use sched::Scheduler;
+pub use cache::BlockCache;
+pub use partition::{Partition, PartitionInfo, PartitionTable, PartitionType, SharedDisk, MAX_PARTITIONS};
+pub use ramdisk::RamDisk;
+pub use overlay::Overlay;
+pub use crypt::Encrypted;
+pub use integrity::Integrity;
 pub use input::{InputDevice, InputEvent};
 pub use config::{
     Pixel,
     Rect,
+    SECTOR_SIZE,
 };
 pub use require::*;
 
 pub type InterruptResult = Result<InterruptOk, InterruptError>;
 pub type IoResult = Result<(), IoError>;
 pub type GraphicResult = Result<(), GraphicError>;
+pub type NetResult = Result<(), NetError>;
@@ -1,8 +1,22 @@
-use core::mem::size_of;
+use core::{mem::size_of, ptr::read_volatile, slice::from_raw_parts_mut};
 
 use tisu_memory::MemoryOp;
+use tisu_sync::{Bool, SpinMutex};
 
-use crate::{Driver, InterruptOk, InterruptResult, VirtHeader, VirtQueue, config::PAGE_SIZE, pool::Pool, queue::{DescFlag, VIRTIO_RING_SIZE}, require::NetDriver};
+use crate::{Driver, InterruptOk, InterruptResult, NetResult, VirtHeader, VirtQueue, config::{NetError, PAGE_SIZE}, pool::Pool, queue::{DescFlag, VIRTIO_RING_SIZE}, require::NetDriver};
+
+/// 收包缓冲区大小，足够放下一个不带 GSO 的以太网帧
+pub(crate) const RX_BUFFER_SIZE : usize = 2048;
+/// 每个收包缓冲区占头部和数据两个描述符
+const RX_BUFFERS : usize = VIRTIO_RING_SIZE / 2;
+/// 发送缓冲区大小，帧复制到此处直到设备发送完毕
+pub(crate) const TX_BUFFER_SIZE : usize = 2048;
+/// 每个发送缓冲区占头部和数据两个描述符
+const TX_BUFFERS : usize = VIRTIO_RING_SIZE / 2;
+/// 以太网帧头长度，不含 VLAN 标签
+pub(crate) const ETHERNET_HEADER : usize = 14;
+/// 未协商 VIRTIO_NET_F_MTU 时的 MTU
+const DEFAULT_MTU : usize = 1500;
 
 #[allow(dead_code)]
 pub struct Net {
@@ -11,6 +25,17 @@ pub struct Net {
     header : &'static mut VirtHeader,
     send_header : Pool<NetHeader>,
     receive_header : Pool<NetHeader>,
+    /// 收包缓冲区，第 i 块属于以描述符 2i 开头的链
+    rx_buffer : *mut u8,
+    /// 设备已填好、尚未取走的帧，为描述符链头和设备写入的长度
+    rx_ready : [(u16, u32);RX_BUFFERS],
+    rx_head : usize,
+    rx_num : usize,
+    /// 发送缓冲区，第 i 块属于以描述符 2i 开头的链
+    tx_buffer : *mut u8,
+    mtu : usize,
+    mutex : SpinMutex,
+    pub int : Bool,
 }
 
 impl Net {
@@ -19,32 +44,143 @@ impl Net {
 		let receive = memory.kernel_page(num).unwrap() as *mut VirtQueue;
         let send = memory.kernel_page(num).unwrap() as *mut VirtQueue;
 		let header = unsafe {&mut *(header)};
-		header.set_feature(!(Feature::MQ.v())).unwrap();
-		header.set_ring_size(VIRTIO_RING_SIZE as u32).unwrap();
+		// 不接受 GSO 等会产生超过缓冲区大小的帧的特性，MTU 过大时也不接受
+		let config = unsafe {read_volatile(header.config_address() as *const Config)};
+		let mut guest = Feature::Mac.v() | Feature::Status.v();
+		if config.mtu as usize + ETHERNET_HEADER <= RX_BUFFER_SIZE {
+			guest |= Feature::Mtu.v();
+		}
+		let features = header.set_feature(guest).unwrap();
 		header.set_page_size(PAGE_SIZE as u32);
-		header.set_pfn(0, (receive as u32) / PAGE_SIZE as u32);
-        header.set_pfn(1, (send as u32) / PAGE_SIZE as u32);
+		header.set_queue(0, VIRTIO_RING_SIZE as u32, (receive as u32) / PAGE_SIZE as u32).unwrap();
+		header.set_queue(1, VIRTIO_RING_SIZE as u32, (send as u32) / PAGE_SIZE as u32).unwrap();
 		header.driver_ok();
-        Self {
+        // 发送完成的描述符在下次发送时回收，不需要中断
+        unsafe {(*send).set_interrupt(false)}
+        let num = RX_BUFFERS * RX_BUFFER_SIZE / PAGE_SIZE;
+        let rx_buffer = memory.kernel_page(num).unwrap();
+        let num = TX_BUFFERS * TX_BUFFER_SIZE / PAGE_SIZE;
+        let tx_buffer = memory.kernel_page(num).unwrap();
+        let mut rt = Self {
             receive : unsafe {&mut *receive},
             send : unsafe {&mut *send},
             header,
             send_header : Pool::default(),
             receive_header : Pool::default(),
+            rx_buffer,
+            rx_ready : [(0, 0);RX_BUFFERS],
+            rx_head : 0,
+            rx_num : 0,
+            tx_buffer,
+            mtu : if features & Feature::Mtu.v() != 0 {config.mtu as usize} else {DEFAULT_MTU},
+            mutex : SpinMutex::new(),
+            int : Bool::new(),
+        };
+        rt.refill();
+        rt
+    }
+
+    /// 在空闲的描述符上放入收包缓冲区并通知设备
+    fn refill(&mut self) {
+        let mut posted = false;
+        while self.receive.is_free(2) {
+            let idx = self.receive.desc_idx() as usize;
+            let header = self.receive_header.get(idx) as *mut NetHeader as u64;
+            let data = unsafe {self.rx_buffer.add(idx / 2 * RX_BUFFER_SIZE)} as u64;
+            self.receive.add_desc(header, HEADER_LEN as u32, DescFlag::Write as u16 | DescFlag::Next as u16);
+            self.receive.add_desc(data, RX_BUFFER_SIZE as u32, DescFlag::Write as u16);
+            self.receive.add_avail(idx as u16);
+            posted = true;
+        }
+        if posted {
+            self.header.notify(0);
+        }
+    }
+
+    /// 不含以太网帧头
+    pub fn mtu(&self)->usize {
+        self.mtu
+    }
+
+    /// 发送队列是否还有空位
+    pub fn can_send(&mut self)->bool {
+        self.mutex.lock();
+        self.reclaim();
+        let rt = self.send.is_free(2);
+        self.mutex.unlock();
+        rt
+    }
+
+    /// 由 fill 直接在发送缓冲区中构造 len 字节的帧并发送
+    pub(crate) fn transmit<R>(&mut self, len : usize, fill : impl FnOnce(&mut [u8])->R)->Result<R, NetError> {
+        if len > TX_BUFFER_SIZE {
+            return Err(NetError::FrameTooLarge);
+        }
+        self.mutex.lock();
+        self.reclaim();
+        if !self.send.is_free(2) {
+            self.mutex.unlock();
+            return Err(NetError::QueueFull);
+        }
+        let idx = self.send.desc_idx() as usize;
+        let header = self.send_header.replace_u64(idx, NetHeader::default());
+        let buffer = unsafe {self.tx_buffer.add(idx / 2 * TX_BUFFER_SIZE)};
+        let rt = fill(unsafe {from_raw_parts_mut(buffer, len)});
+        self.send.add_desc(header, HEADER_LEN as u32, DescFlag::Next as u16);
+        self.send.add_desc(buffer as u64, len as u32, 0);
+        self.send.add_avail(idx as u16);
+        self.mutex.unlock();
+        self.header.notify(1);
+        Ok(rt)
+    }
+
+    /// 回收设备已发送完的描述符
+    fn reclaim(&mut self) {
+        while self.send.is_pending() {
+            let elem = self.send.next_elem();
+            self.send.free_desc(elem.id as u16);
         }
     }
+
+    /// 取出设备已填好的帧，暂存待 receive 复制，返回是否有新帧
+    fn collect(&mut self)->bool {
+        let mut received = false;
+        self.mutex.lock();
+        while self.rx_num < RX_BUFFERS && self.receive.is_pending() {
+            let elem = self.receive.next_elem();
+            self.rx_ready[(self.rx_head + self.rx_num) % RX_BUFFERS] = (elem.id as u16, elem.len);
+            self.rx_num += 1;
+            received = true;
+        }
+        self.mutex.unlock();
+        received
+    }
 }
 
 impl NetDriver for Net {
-    fn send(&mut self, data : &[u8]) {
-        let mut flag = DescFlag::Next as u16;
-        let header = self.send_header.get(self.send.desc_idx() as usize);
-        self.send.add_avail();
-        let len = size_of::<NetHeader>() as u32;
-        self.send.add_desc(header as *mut NetHeader as *mut u8 as u64, len, flag);
-        flag = DescFlag::Write as u16;
-        self.send.add_desc(data as *const [u8] as *const u8 as u64, data.len() as u32, flag);
-        self.header.notify(1);
+    fn send(&mut self, data : &[u8])->NetResult {
+        self.transmit(data.len(), |buffer| buffer.copy_from_slice(data))
+    }
+
+    fn receive(&mut self, data : &mut [u8])->Option<Result<usize, NetError>> {
+        self.collect();
+        if self.rx_num == 0 {
+            return None;
+        }
+        let (head, len) = self.rx_ready[self.rx_head];
+        let len = frame_len(len);
+        if data.len() < len {
+            return Some(Err(NetError::BufferTooSmall(len)));
+        }
+        let frame = unsafe {self.rx_buffer.add(head as usize / 2 * RX_BUFFER_SIZE)};
+        unsafe {data.as_mut_ptr().copy_from(frame, len)}
+        self.mutex.lock();
+        self.rx_head = (self.rx_head + 1) % RX_BUFFERS;
+        self.rx_num -= 1;
+        self.receive.free_desc(head);
+        self.mutex.unlock();
+        self.refill();
+        Some(Ok(len))
     }
 
     fn mac(&self)->usize {
@@ -60,37 +196,70 @@ impl NetDriver for Net {
 }
 
 impl Driver for Net {
+    /// 收下设备填好的帧，由 receive 取出
     fn handler(&mut self)->crate::InterruptResult {
+        if !self.int.pop() {
+            return Ok(InterruptOk::Net);
+        }
+        self.collect();
         InterruptResult::Ok(InterruptOk::Net)
     }
 
     fn pending(&mut self)->crate::InterruptResult {
+        self.int.set_true();
         InterruptResult::Ok(InterruptOk::Net)
     }
+
+    /// receive 本就会直接检查队列，轮询时只需关闭中断
+    fn set_polling(&mut self, polling : bool) {
+        self.receive.set_interrupt(!polling);
+    }
 }
 
 #[repr(u32)]
+#[allow(dead_code)]
 pub enum Feature {
+    Mtu = 3,
+    Mac = 5,
+    /// 接收时可将一个帧放入多个缓冲区，头部带 num_buffers
+    MrgRxbuf = 15,
+    Status = 16,
     MQ = 22,
 }
 
 impl Feature {
     pub fn v(self)->u32 {
-        self as u32
+        1 << self as u32
     }
 }
 
+/// virtio-net 头部，每个收发的帧前都有一个
+#[repr(C)]
 #[derive(Clone, Copy, Default)]
 #[allow(dead_code)]
 pub struct NetHeader {
-    ntype : u8,
-    len : u16,
-    size : u16,
+    flags : u8,
+    gso_type : u8,
+    hdr_len : u16,
+    gso_size : u16,
     csum_start : u16,
     csum_offset : u16,
-    num_buffer : u16,
+    /// 只在 VERSION_1 设备或协商了 MRG_RXBUF 时存在，本驱动不会用到
+    num_buffers : u16,
 }
 
+/// 设备实际读写的头部长度
+/// 只支持 legacy MMIO 且不协商 MRG_RXBUF，头部不含 num_buffers
+const HEADER_LEN : usize = 10;
+
+/// 设备写入的总长度去掉头部即为帧长
+fn frame_len(used : u32)->usize {
+    (used as usize).saturating_sub(HEADER_LEN)
+}
+
+/// 设备配置空间，位于寄存器偏移 0x100 处
+#[repr(C)]
+#[derive(Clone, Copy)]
 #[allow(dead_code)]
 pub struct Config {
     mac : [u8;6],
@@ -99,3 +268,57 @@ pub struct Config {
     mtu : u16,
 }
 
+// 与规范中的内存布局一致
+const _ : [(); 12] = [(); size_of::<NetHeader>()];
+const _ : [(); 12] = [(); size_of::<Config>()];
+
+
+#[cfg(test)]
+mod tests {
+    use core::{mem::transmute, ptr::addr_of};
+
+    use super::*;
+
+    fn header()->NetHeader {
+        NetHeader {
+            flags : 0x01,
+            gso_type : 0x02,
+            hdr_len : 0x0403,
+            gso_size : 0x0605,
+            csum_start : 0x0807,
+            csum_offset : 0x0a09,
+            num_buffers : 0x0c0b,
+        }
+    }
+
+    #[test]
+    fn header_offsets() {
+        let h = header();
+        let base = addr_of!(h) as usize;
+        let offsets = [
+            addr_of!(h.flags) as usize,
+            addr_of!(h.gso_type) as usize,
+            addr_of!(h.hdr_len) as usize,
+            addr_of!(h.gso_size) as usize,
+            addr_of!(h.csum_start) as usize,
+            addr_of!(h.csum_offset) as usize,
+            addr_of!(h.num_buffers) as usize,
+        ].map(|addr| addr - base);
+        assert_eq!(offsets, [0, 1, 2, 4, 6, 8, 10]);
+    }
+
+    /// 规范要求各字段为小端序
+    #[test]
+    fn header_bytes() {
+        let bytes : [u8;12] = unsafe {transmute(header())};
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn header_len() {
+        let h = header();
+        assert_eq!(addr_of!(h.num_buffers) as usize - addr_of!(h) as usize, HEADER_LEN);
+        assert_eq!(frame_len(HEADER_LEN as u32 + 60), 60);
+        assert_eq!(frame_len(4), 0);
+    }
+}
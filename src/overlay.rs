@@ -0,0 +1,292 @@
+//! # 写时复制覆盖层
+//! 读取落到只读的底层设备，写入落到差异设备，用位图记录哪些块已被改写
+//! 差异可以整体丢弃，也可以提交回底层设备
+
+use core::{cmp::min, ptr::write_bytes, slice::from_raw_parts_mut};
+
+use tisu_memory::MemoryOp;
+
+use crate::{InterruptResult, IoResult, config::{IoError, PAGE_SIZE, SECTOR_SIZE}, require::{BlockDriver, Driver}};
+
+pub struct Overlay<B : BlockDriver, D : BlockDriver> {
+    base : B,
+    delta : D,
+    /// 每个逻辑块一位，置位表示数据在差异设备中
+    bitmap : &'static mut [u8],
+}
+
+impl<B : BlockDriver, D : BlockDriver> Overlay<B, D> {
+    /// 差异设备容量不能小于底层设备，两者块大小须一致
+    pub fn new(base : B, delta : D, memory : &mut impl MemoryOp)->Result<Self, IoError> {
+        if delta.capacity() < base.capacity() {
+            return Err(IoError::OutOfRange);
+        }
+        if delta.block_size() != base.block_size() || base.block_size() > PAGE_SIZE {
+            return Err(IoError::Unsupported);
+        }
+        let blocks = base.capacity() * SECTOR_SIZE / base.block_size();
+        let size = blocks.div_ceil(8);
+        let bitmap = memory.kernel_page(size.div_ceil(PAGE_SIZE)).ok_or(IoError::NoMemory)?;
+        unsafe {write_bytes(bitmap, 0, size)}
+        Ok(Self {
+            base,
+            delta,
+            bitmap : unsafe {from_raw_parts_mut(bitmap, size)},
+        })
+    }
+
+    /// 丢弃所有改动，之后读到的都是底层设备的数据
+    pub fn revert(&mut self) {
+        for byte in self.bitmap.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    /// 将改动写回底层设备并清空差异
+    pub fn commit(&mut self)->IoResult {
+        let block = self.base.block_size();
+        let mut buffer = [0;PAGE_SIZE];
+        let buffer = &mut buffer[..block];
+        for idx in 0..self.bitmap.len() * 8 {
+            if !self.is_dirty(idx) {
+                continue;
+            }
+            self.delta.sync_read(idx * block, block, buffer)?;
+            self.base.sync_write(idx * block, block, buffer)?;
+            self.set_dirty(idx, false);
+        }
+        self.base.flush()
+    }
+
+    fn is_dirty(&self, idx : usize)->bool {
+        self.bitmap[idx / 8] & (1 << (idx % 8)) != 0
+    }
+
+    fn set_dirty(&mut self, idx : usize, dirty : bool) {
+        if dirty {
+            self.bitmap[idx / 8] |= 1 << (idx % 8);
+        }
+        else {
+            self.bitmap[idx / 8] &= !(1 << (idx % 8));
+        }
+    }
+
+    fn mark(&mut self, offset : usize, len : usize) {
+        let block = self.block_size();
+        for idx in offset / block..(offset + len) / block {
+            self.set_dirty(idx, true);
+        }
+    }
+}
+
+impl<B : BlockDriver, D : BlockDriver> Driver for Overlay<B, D> {
+    fn handler(&mut self)->InterruptResult {
+        self.base.handler()?;
+        self.delta.handler()
+    }
+
+    fn pending(&mut self)->InterruptResult {
+        self.base.pending()?;
+        self.delta.pending()
+    }
+
+    fn set_polling(&mut self, polling : bool) {
+        self.base.set_polling(polling);
+        self.delta.set_polling(polling);
+    }
+}
+
+impl<B : BlockDriver, D : BlockDriver> BlockDriver for Overlay<B, D> {
+    fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
+        self.check_range(offset, len)?;
+        self.delta.sync_write(offset, len, data)?;
+        self.mark(offset, len);
+        Ok(())
+    }
+
+    /// 连续的块若来自同一设备则合并为一次读取
+    fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        let block = self.block_size();
+        let mut done = 0;
+        while done < len {
+            let idx = (offset + done) / block;
+            let dirty = self.is_dirty(idx);
+            let mut n = block;
+            while done + n < len && self.is_dirty(idx + n / block) == dirty {
+                n += block;
+            }
+            n = min(n, len - done);
+            let buffer = &mut data[done..done + n];
+            if dirty {
+                self.delta.sync_read(offset + done, n, buffer)?;
+            }
+            else {
+                self.base.sync_read(offset + done, n, buffer)?;
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self)->IoResult {
+        self.delta.flush()
+    }
+
+    fn discard(&mut self, offset : usize, len : usize)->IoResult {
+        self.write_zeroes(offset, len, true)
+    }
+
+    fn write_zeroes(&mut self, offset : usize, len : usize, unmap : bool)->IoResult {
+        self.check_range(offset, len)?;
+        self.delta.fill_zeroes(offset, len, unmap)?;
+        self.mark(offset, len);
+        Ok(())
+    }
+
+    fn capacity(&self)->usize {
+        self.base.capacity()
+    }
+
+    fn block_size(&self)->usize {
+        self.base.block_size()
+    }
+
+    fn physical_block_size(&self)->usize {
+        self.base.physical_block_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{boxed::Box, vec};
+
+    use super::*;
+    use crate::{RamDisk, ramdisk::tests::disk};
+
+    const SECTORS : usize = 8;
+
+    /// 记录每个扇区被写入的次数，不实现 write_zeroes
+    struct Counting {
+        disk : RamDisk,
+        writes : [usize;SECTORS],
+    }
+
+    impl Counting {
+        fn new()->Self {
+            Self {
+                disk : disk(SECTORS),
+                writes : [0;SECTORS],
+            }
+        }
+    }
+
+    impl Driver for Counting {
+        fn handler(&mut self)->InterruptResult {
+            self.disk.handler()
+        }
+
+        fn pending(&mut self)->InterruptResult {
+            self.disk.pending()
+        }
+    }
+
+    impl BlockDriver for Counting {
+        fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
+            self.disk.sync_write(offset, len, data)?;
+            for idx in offset / SECTOR_SIZE..(offset + len) / SECTOR_SIZE {
+                self.writes[idx] += 1;
+            }
+            Ok(())
+        }
+
+        fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+            self.disk.sync_read(offset, len, data)
+        }
+
+        fn capacity(&self)->usize {
+            self.disk.capacity()
+        }
+    }
+
+    /// 底层设备每个扇区填入扇区号加一
+    fn overlay<D : BlockDriver>(delta : D)->Overlay<Counting, D> {
+        let mut base = Counting::new();
+        for idx in 0..SECTORS {
+            base.sync_write(idx * SECTOR_SIZE, SECTOR_SIZE, &[idx as u8 + 1;SECTOR_SIZE]).unwrap();
+        }
+        base.writes = [0;SECTORS];
+        let size = SECTORS.div_ceil(8);
+        Overlay {
+            base,
+            delta,
+            bitmap : Box::leak(vec![0;size].into_boxed_slice()),
+        }
+    }
+
+    fn sector(overlay : &mut impl BlockDriver, idx : usize)->[u8;SECTOR_SIZE] {
+        let mut buffer = [0;SECTOR_SIZE];
+        overlay.sync_read(idx * SECTOR_SIZE, SECTOR_SIZE, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn read_mixes_base_and_delta() {
+        let mut overlay = overlay(disk(SECTORS));
+        overlay.sync_write(2 * SECTOR_SIZE, 2 * SECTOR_SIZE, &[0xaa;2 * SECTOR_SIZE]).unwrap();
+        overlay.sync_write(5 * SECTOR_SIZE, SECTOR_SIZE, &[0xbb;SECTOR_SIZE]).unwrap();
+        let mut buffer = [0;SECTORS * SECTOR_SIZE];
+        overlay.sync_read(0, SECTORS * SECTOR_SIZE, &mut buffer).unwrap();
+        let expect = [1, 2, 0xaa, 0xaa, 5, 0xbb, 7, 8];
+        for (idx, &byte) in expect.iter().enumerate() {
+            assert!(buffer[idx * SECTOR_SIZE..(idx + 1) * SECTOR_SIZE].iter().all(|&b| b == byte), "sector {}", idx);
+        }
+        assert_eq!(overlay.base.writes, [0;SECTORS]);
+        overlay.revert();
+        assert_eq!(sector(&mut overlay, 2), [3;SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn partial_copy_up() {
+        let mut overlay = overlay(disk(SECTORS));
+        overlay.sync_write(3 * SECTOR_SIZE, SECTOR_SIZE, &[0xcc;SECTOR_SIZE]).unwrap();
+        let mut buffer = [0;3 * SECTOR_SIZE];
+        overlay.sync_read(2 * SECTOR_SIZE, 3 * SECTOR_SIZE, &mut buffer).unwrap();
+        assert!(buffer[..SECTOR_SIZE].iter().all(|&b| b == 3));
+        assert!(buffer[SECTOR_SIZE..2 * SECTOR_SIZE].iter().all(|&b| b == 0xcc));
+        assert!(buffer[2 * SECTOR_SIZE..].iter().all(|&b| b == 5));
+        assert_eq!(sector(&mut overlay.delta, 2), [0;SECTOR_SIZE]);
+        assert_eq!(sector(&mut overlay.delta, 4), [0;SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn commit_writes_dirty_blocks() {
+        let mut overlay = overlay(disk(SECTORS));
+        overlay.sync_write(SECTOR_SIZE, SECTOR_SIZE, &[0xdd;SECTOR_SIZE]).unwrap();
+        overlay.sync_write(6 * SECTOR_SIZE, SECTOR_SIZE, &[0xee;SECTOR_SIZE]).unwrap();
+        overlay.commit().unwrap();
+        assert_eq!(overlay.base.writes, [0, 1, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(sector(&mut overlay.base, 1), [0xdd;SECTOR_SIZE]);
+        assert_eq!(sector(&mut overlay.base, 6), [0xee;SECTOR_SIZE]);
+        overlay.commit().unwrap();
+        assert_eq!(overlay.base.writes, [0, 1, 0, 0, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn write_zeroes_falls_back() {
+        let mut delta = Counting::new();
+        delta.sync_write(0, SECTORS * SECTOR_SIZE, &[0xff;SECTORS * SECTOR_SIZE]).unwrap();
+        let mut overlay = overlay(delta);
+        overlay.discard(2 * SECTOR_SIZE, 3 * SECTOR_SIZE).unwrap();
+        assert_eq!(sector(&mut overlay, 1), [2;SECTOR_SIZE]);
+        for idx in 2..5 {
+            assert_eq!(sector(&mut overlay, idx), [0;SECTOR_SIZE]);
+        }
+        assert_eq!(sector(&mut overlay, 5), [6;SECTOR_SIZE]);
+    }
+}
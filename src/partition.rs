@@ -0,0 +1,484 @@
+//! # 分区表
+//! 解析 MBR 与 GPT，每个分区作为独立的块设备使用
+
+use core::{cell::UnsafeCell, cmp::min, convert::TryInto};
+
+use tisu_sync::SpinMutex;
+
+use crate::{InterruptResult, IoResult, config::{IoError, PAGE_SIZE, SECTOR_SIZE}, crc::Crc32, require::{BlockDriver, Driver}};
+
+/// 最多记录的分区数，超出的分区被忽略
+pub const MAX_PARTITIONS : usize = 32;
+
+/// MBR 中表示磁盘使用 GPT 的保护分区类型
+const MBR_GPT_PROTECTIVE : u8 = 0xee;
+const MBR_ENTRY_OFFSET : usize = 446;
+const MBR_ENTRY_SIZE : usize = 16;
+const GPT_SIGNATURE : &[u8] = b"EFI PART";
+const GPT_HEADER_SIZE : usize = 92;
+const GPT_ENTRY_SIZE : usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionType {
+    /// MBR 分区类型号
+    Mbr(u8),
+    /// GPT 分区类型 GUID
+    Gpt([u8;16]),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PartitionInfo {
+    /// 起始扇区（512 字节）
+    pub start : usize,
+    /// 扇区数（512 字节）
+    pub sectors : usize,
+    pub ptype : PartitionType,
+    /// GPT 分区的唯一 GUID，MBR 分区为 0
+    pub guid : [u8;16],
+}
+
+impl Default for PartitionInfo {
+    fn default() -> Self {
+        Self {
+            start : 0,
+            sectors : 0,
+            ptype : PartitionType::Mbr(0),
+            guid : [0;16],
+        }
+    }
+}
+
+pub struct PartitionTable {
+    partitions : [PartitionInfo;MAX_PARTITIONS],
+    num : usize,
+}
+
+impl PartitionTable {
+    /// 读取设备的分区表，GPT 主表损坏时使用备份表
+    pub fn parse<D : BlockDriver>(disk : &mut D)->Result<Self, IoError> {
+        let block = disk.block_size();
+        if !(SECTOR_SIZE..=PAGE_SIZE).contains(&block) {
+            return Err(IoError::Unsupported);
+        }
+        let mut buffer = [0;PAGE_SIZE];
+        disk.sync_read(0, block, &mut buffer[..block])?;
+        if buffer[510] != 0x55 || buffer[511] != 0xaa {
+            return Err(IoError::NoPartitionTable);
+        }
+        let mut table = Self {
+            partitions : [PartitionInfo::default();MAX_PARTITIONS],
+            num : 0,
+        };
+        for i in 0..4 {
+            let st = MBR_ENTRY_OFFSET + i * MBR_ENTRY_SIZE;
+            let entry = &buffer[st..st + MBR_ENTRY_SIZE];
+            let ptype = entry[4];
+            if ptype == 0 {
+                continue;
+            }
+            if ptype == MBR_GPT_PROTECTIVE {
+                let last = disk.capacity() * SECTOR_SIZE / block - 1;
+                return match table.read_gpt(disk, 1, &mut buffer) {
+                    Err(IoError::Corrupted) => table.read_gpt(disk, last, &mut buffer),
+                    rt => rt,
+                }.map(|_| table);
+            }
+            let lba = read_u32(entry, 8) as usize;
+            let num = read_u32(entry, 12) as usize;
+            table.push(disk, PartitionInfo {
+                start : lba * block / SECTOR_SIZE,
+                sectors : num * block / SECTOR_SIZE,
+                ptype : PartitionType::Mbr(ptype),
+                guid : [0;16],
+            })?;
+        }
+        Ok(table)
+    }
+
+    pub fn partitions(&self)->&[PartitionInfo] {
+        &self.partitions[..self.num]
+    }
+
+    fn push<D : BlockDriver>(&mut self, disk : &D, info : PartitionInfo)->IoResult {
+        if info.start + info.sectors > disk.capacity() {
+            return Err(IoError::Corrupted);
+        }
+        if self.num < MAX_PARTITIONS {
+            self.partitions[self.num] = info;
+            self.num += 1;
+        }
+        Ok(())
+    }
+
+    /// 读取位于 lba 的 GPT 头及其分区项，校验两者的 CRC32
+    fn read_gpt<D : BlockDriver>(&mut self, disk : &mut D, lba : usize, buffer : &mut [u8])->IoResult {
+        self.num = 0;
+        let block = disk.block_size();
+        disk.sync_read(lba * block, block, &mut buffer[..block])?;
+        let header = &buffer[..block];
+        let size = read_u32(header, 12) as usize;
+        if &header[..8] != GPT_SIGNATURE || size < GPT_HEADER_SIZE || size > block {
+            return Err(IoError::Corrupted);
+        }
+        let mut crc = Crc32::new();
+        crc.update(&header[..16]);
+        crc.update(&[0;4]);
+        crc.update(&header[20..size]);
+        if crc.finish() != read_u32(header, 16) || read_u64(header, 24) as usize != lba {
+            return Err(IoError::Corrupted);
+        }
+        let entry_lba = read_u64(header, 72) as usize;
+        let entry_num = read_u32(header, 80) as usize;
+        let entry_size = read_u32(header, 84) as usize;
+        let entry_crc = read_u32(header, 88);
+        if entry_size < GPT_ENTRY_SIZE || !entry_size.is_power_of_two() || entry_size > block {
+            return Err(IoError::Corrupted);
+        }
+        let total = entry_num * entry_size;
+        let mut crc = Crc32::new();
+        let mut done = 0;
+        while done < total {
+            disk.sync_read(entry_lba * block + done, block, &mut buffer[..block])?;
+            let len = min(block, total - done);
+            crc.update(&buffer[..len]);
+            for entry in buffer[..len].chunks(entry_size) {
+                let ptype : [u8;16] = entry[..16].try_into().unwrap();
+                if ptype == [0;16] {
+                    continue;
+                }
+                let first = read_u64(entry, 32) as usize;
+                let last = read_u64(entry, 40) as usize;
+                if last < first {
+                    return Err(IoError::Corrupted);
+                }
+                self.push(disk, PartitionInfo {
+                    start : first * block / SECTOR_SIZE,
+                    sectors : (last - first + 1) * block / SECTOR_SIZE,
+                    ptype : PartitionType::Gpt(ptype),
+                    guid : entry[16..32].try_into().unwrap(),
+                })?;
+            }
+            done += len;
+        }
+        if crc.finish() != entry_crc {
+            return Err(IoError::Corrupted);
+        }
+        Ok(())
+    }
+}
+
+fn read_u32(data : &[u8], offset : usize)->u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data : &[u8], offset : usize)->u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// 多个分区共用的磁盘，请求在自旋锁内下发
+/// handler 与 pending 不取锁，请求等待完成时中断仍能经本结构或任一分区送达磁盘
+pub struct SharedDisk<D : BlockDriver> {
+    disk : UnsafeCell<D>,
+    mutex : UnsafeCell<SpinMutex>,
+}
+
+unsafe impl<D : BlockDriver + Send> Sync for SharedDisk<D> {}
+
+impl<D : BlockDriver> SharedDisk<D> {
+    pub fn new(disk : D)->Self {
+        Self {
+            disk : UnsafeCell::new(disk),
+            mutex : UnsafeCell::new(SpinMutex::new()),
+        }
+    }
+
+    pub fn into_inner(self)->D {
+        self.disk.into_inner()
+    }
+
+    /// 处理中断，不取锁
+    pub fn handler(&self)->InterruptResult {
+        unsafe {(*self.disk.get()).handler()}
+    }
+
+    /// 通知中断，不取锁
+    pub fn pending(&self)->InterruptResult {
+        unsafe {(*self.disk.get()).pending()}
+    }
+
+    /// 持锁访问磁盘
+    fn lock<R>(&self, f : impl FnOnce(&mut D)->R)->R {
+        let mutex = unsafe {&mut *self.mutex.get()};
+        mutex.lock();
+        let rt = f(unsafe {&mut *self.disk.get()});
+        mutex.unlock();
+        rt
+    }
+}
+
+/// 分区块设备，偏移相对分区起始，请求不能越过分区边界
+pub struct Partition<'a, D : BlockDriver> {
+    disk : &'a SharedDisk<D>,
+    /// 起始字节偏移
+    start : usize,
+    /// 扇区数（512 字节）
+    sectors : usize,
+}
+
+impl<'a, D : BlockDriver> Partition<'a, D> {
+    pub fn new(disk : &'a SharedDisk<D>, info : &PartitionInfo)->Self {
+        Self {
+            disk,
+            start : info.start * SECTOR_SIZE,
+            sectors : info.sectors,
+        }
+    }
+}
+
+impl<'a, D : BlockDriver> Driver for Partition<'a, D> {
+    fn handler(&mut self)->InterruptResult {
+        self.disk.handler()
+    }
+
+    fn pending(&mut self)->InterruptResult {
+        self.disk.pending()
+    }
+
+    fn set_polling(&mut self, polling : bool) {
+        self.disk.lock(|disk| disk.set_polling(polling))
+    }
+}
+
+impl<'a, D : BlockDriver> BlockDriver for Partition<'a, D> {
+    fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
+        self.check_range(offset, len)?;
+        let offset = self.start + offset;
+        self.disk.lock(|disk| disk.sync_write(offset, len, data))
+    }
+
+    fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+        self.check_range(offset, len)?;
+        let offset = self.start + offset;
+        self.disk.lock(|disk| disk.sync_read(offset, len, data))
+    }
+
+    fn sync_writev(&mut self, offset : usize, data : &[&[u8]])->IoResult {
+        self.check_range(offset, data.iter().map(|b| b.len()).sum())?;
+        let offset = self.start + offset;
+        self.disk.lock(|disk| disk.sync_writev(offset, data))
+    }
+
+    fn sync_readv(&mut self, offset : usize, data : &mut [&mut [u8]])->IoResult {
+        self.check_range(offset, data.iter().map(|b| b.len()).sum())?;
+        let offset = self.start + offset;
+        self.disk.lock(|disk| disk.sync_readv(offset, data))
+    }
+
+    fn flush(&mut self)->IoResult {
+        self.disk.lock(|disk| disk.flush())
+    }
+
+    fn discard(&mut self, offset : usize, len : usize)->IoResult {
+        self.check_range(offset, len)?;
+        let offset = self.start + offset;
+        self.disk.lock(|disk| disk.discard(offset, len))
+    }
+
+    fn write_zeroes(&mut self, offset : usize, len : usize, unmap : bool)->IoResult {
+        self.check_range(offset, len)?;
+        let offset = self.start + offset;
+        self.disk.lock(|disk| disk.write_zeroes(offset, len, unmap))
+    }
+
+    fn capacity(&self)->usize {
+        self.sectors
+    }
+
+    fn block_size(&self)->usize {
+        self.disk.lock(|disk| disk.block_size())
+    }
+
+    fn physical_block_size(&self)->usize {
+        self.disk.lock(|disk| disk.physical_block_size())
+    }
+
+    fn read_only(&self)->bool {
+        self.disk.lock(|disk| disk.read_only())
+    }
+
+    fn max_segments(&self)->usize {
+        self.disk.lock(|disk| disk.max_segments())
+    }
+
+    fn max_segment_size(&self)->usize {
+        self.disk.lock(|disk| disk.max_segment_size())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use super::*;
+    use crate::{InterruptOk, RamDisk, ramdisk::tests::disk};
+
+    const SECTORS : usize = 128;
+    const ENTRY_NUM : usize = 4;
+
+    fn mbr(disk : &mut RamDisk, entries : &[(u8, u32, u32)]) {
+        let mut sector = [0;SECTOR_SIZE];
+        for (i, &(ptype, lba, num)) in entries.iter().enumerate() {
+            let st = MBR_ENTRY_OFFSET + i * MBR_ENTRY_SIZE;
+            sector[st + 4] = ptype;
+            sector[st + 8..st + 12].copy_from_slice(&lba.to_le_bytes());
+            sector[st + 12..st + 16].copy_from_slice(&num.to_le_bytes());
+        }
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+        disk.sync_write(0, SECTOR_SIZE, &sector).unwrap();
+    }
+
+    /// 主表位于 LBA 1、分区项位于 LBA 2，备份分区项与备份头位于磁盘末尾两个扇区
+    fn gpt(disk : &mut RamDisk) {
+        mbr(disk, &[(MBR_GPT_PROTECTIVE, 1, SECTORS as u32 - 1)]);
+        let mut entries = [0;ENTRY_NUM * GPT_ENTRY_SIZE];
+        for (i, &(first, last)) in [(34u64, 63u64), (64, 100)].iter().enumerate() {
+            let entry = &mut entries[i * GPT_ENTRY_SIZE..(i + 1) * GPT_ENTRY_SIZE];
+            entry[..16].copy_from_slice(&[0x11 * (i as u8 + 1);16]);
+            entry[16..32].copy_from_slice(&[0xa0 + i as u8;16]);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        let mut crc = Crc32::new();
+        crc.update(&entries);
+        let entry_crc = crc.finish();
+        let last = SECTORS as u64 - 1;
+        for &(lba, alternate, entry_lba) in [(1, last, 2), (last, 1, last - 1)].iter() {
+            let mut header = [0;SECTOR_SIZE];
+            header[..8].copy_from_slice(GPT_SIGNATURE);
+            header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+            header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+            header[24..32].copy_from_slice(&lba.to_le_bytes());
+            header[32..40].copy_from_slice(&alternate.to_le_bytes());
+            header[40..48].copy_from_slice(&34u64.to_le_bytes());
+            header[48..56].copy_from_slice(&(last - 2).to_le_bytes());
+            header[72..80].copy_from_slice(&entry_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&(ENTRY_NUM as u32).to_le_bytes());
+            header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+            header[88..92].copy_from_slice(&entry_crc.to_le_bytes());
+            let mut crc = Crc32::new();
+            crc.update(&header[..GPT_HEADER_SIZE]);
+            header[16..20].copy_from_slice(&crc.finish().to_le_bytes());
+            disk.sync_write(lba as usize * SECTOR_SIZE, SECTOR_SIZE, &header).unwrap();
+            disk.sync_write(entry_lba as usize * SECTOR_SIZE, SECTOR_SIZE, &entries).unwrap();
+        }
+    }
+
+    fn check_gpt(table : &PartitionTable) {
+        let p = table.partitions();
+        assert_eq!(p.len(), 2);
+        assert_eq!((p[0].start, p[0].sectors), (34, 30));
+        assert_eq!(p[0].ptype, PartitionType::Gpt([0x11;16]));
+        assert_eq!((p[1].start, p[1].sectors), (64, 37));
+        assert_eq!(p[1].ptype, PartitionType::Gpt([0x22;16]));
+        assert_eq!(p[1].guid, [0xa1;16]);
+    }
+
+    /// 翻转 lba 处扇区中的一个字节
+    fn corrupt(disk : &mut RamDisk, lba : usize, offset : usize) {
+        let mut sector = [0;SECTOR_SIZE];
+        disk.sync_read(lba * SECTOR_SIZE, SECTOR_SIZE, &mut sector).unwrap();
+        sector[offset] ^= 1;
+        disk.sync_write(lba * SECTOR_SIZE, SECTOR_SIZE, &sector).unwrap();
+    }
+
+    #[test]
+    fn primary_gpt() {
+        let mut disk = disk(SECTORS);
+        gpt(&mut disk);
+        check_gpt(&PartitionTable::parse(&mut disk).unwrap());
+    }
+
+    #[test]
+    fn backup_gpt() {
+        let mut disk = disk(SECTORS);
+        gpt(&mut disk);
+        corrupt(&mut disk, 1, 40);
+        check_gpt(&PartitionTable::parse(&mut disk).unwrap());
+        corrupt(&mut disk, SECTORS - 2, 0);
+        assert!(matches!(PartitionTable::parse(&mut disk), Err(IoError::Corrupted)));
+    }
+
+    #[test]
+    fn mbr_only() {
+        let mut disk = disk(SECTORS);
+        mbr(&mut disk, &[(0x83, 8, 16), (0, 0, 0), (0x0c, 24, 32)]);
+        let table = PartitionTable::parse(&mut disk).unwrap();
+        let p = table.partitions();
+        assert_eq!(p.len(), 2);
+        assert_eq!((p[0].start, p[0].sectors, p[0].ptype), (8, 16, PartitionType::Mbr(0x83)));
+        assert_eq!((p[1].start, p[1].sectors, p[1].ptype), (24, 32, PartitionType::Mbr(0x0c)));
+        mbr(&mut disk, &[(0x83, 100, 64)]);
+        assert!(matches!(PartitionTable::parse(&mut disk), Err(IoError::Corrupted)));
+    }
+
+    /// 请求一直等到 handler 被调用才完成，模拟中断驱动的磁盘
+    struct Waiting<'a> {
+        started : &'a AtomicBool,
+        done : &'a AtomicBool,
+    }
+
+    impl<'a> Driver for Waiting<'a> {
+        fn handler(&mut self)->InterruptResult {
+            self.done.store(true, Ordering::Release);
+            Ok(InterruptOk::Block)
+        }
+
+        fn pending(&mut self)->InterruptResult {
+            Ok(InterruptOk::Block)
+        }
+    }
+
+    impl<'a> BlockDriver for Waiting<'a> {
+        fn sync_write(&mut self, _offset : usize, _len : usize, _data : &[u8])->IoResult {
+            Err(IoError::Unsupported)
+        }
+
+        fn sync_read(&mut self, _offset : usize, len : usize, data : &mut [u8])->IoResult {
+            self.started.store(true, Ordering::Release);
+            while !self.done.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+            }
+            data[..len].iter_mut().for_each(|b| *b = 0x5a);
+            Ok(())
+        }
+
+        fn capacity(&self)->usize {
+            SECTORS
+        }
+    }
+
+    #[test]
+    fn handler_during_request() {
+        let (started, done) = (AtomicBool::new(false), AtomicBool::new(false));
+        let disk = SharedDisk::new(Waiting { started : &started, done : &done });
+        let info = PartitionInfo { start : 8, sectors : 8, ..Default::default() };
+        let mut reader = Partition::new(&disk, &info);
+        let mut other = Partition::new(&disk, &info);
+        thread::scope(|s| {
+            let request = s.spawn(move || {
+                let mut data = [0;SECTOR_SIZE];
+                reader.sync_read(0, SECTOR_SIZE, &mut data).map(|_| data[0])
+            });
+            while !started.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+            }
+            other.pending().unwrap();
+            other.handler().unwrap();
+            assert!(matches!(request.join().unwrap(), Ok(0x5a)));
+        });
+    }
+}
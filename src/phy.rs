@@ -0,0 +1,78 @@
+//! # smoltcp 适配
+//! 为 Net 实现 smoltcp 的 Device，开启 smoltcp 特性后可用
+
+use core::cmp::min;
+
+use smoltcp::{phy::{ChecksumCapabilities, Device, DeviceCapabilities, Medium, RxToken, TxToken}, time::Instant};
+
+use crate::{net::{ETHERNET_HEADER, Net, RX_BUFFER_SIZE, TX_BUFFER_SIZE}, require::NetDriver};
+
+/// 收到的帧在生成令牌时已从设备取出
+pub struct NetRxToken {
+    buffer : [u8;RX_BUFFER_SIZE],
+    len : usize,
+}
+
+pub struct NetTxToken<'a> {
+    net : &'a mut Net,
+}
+
+impl RxToken for NetRxToken {
+    fn consume<R, F>(self, f : F)->R where F : FnOnce(&[u8])->R {
+        f(&self.buffer[..self.len])
+    }
+}
+
+impl<'a> TxToken for NetTxToken<'a> {
+    /// 帧直接构造在发送缓冲区中，队列已满时帧被丢弃
+    fn consume<R, F>(self, len : usize, f : F)->R where F : FnOnce(&mut [u8])->R {
+        let mut f = Some(f);
+        match self.net.transmit(len, |buffer| (f.take().unwrap())(buffer)) {
+            Ok(rt) => rt,
+            Err(_) => {
+                let mut buffer = [0;TX_BUFFER_SIZE];
+                (f.take().unwrap())(&mut buffer[..min(len, TX_BUFFER_SIZE)])
+            }
+        }
+    }
+}
+
+impl Device for Net {
+    type RxToken<'a> = NetRxToken where Self : 'a;
+    type TxToken<'a> = NetTxToken<'a> where Self : 'a;
+
+    /// 发送队列已满时不取帧，以免回复被丢弃，帧留在设备中等下次轮询
+    fn receive(&mut self, _timestamp : Instant)->Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        if !self.can_send() {
+            return None;
+        }
+        let mut rx = NetRxToken {
+            buffer : [0;RX_BUFFER_SIZE],
+            len : 0,
+        };
+        // 缓冲区与收包缓冲区等大，不会出现 BufferTooSmall
+        rx.len = match NetDriver::receive(self, &mut rx.buffer) {
+            Some(Ok(len)) => len,
+            _ => return None,
+        };
+        Some((rx, NetTxToken { net : self }))
+    }
+
+    fn transmit(&mut self, _timestamp : Instant)->Option<Self::TxToken<'_>> {
+        if self.can_send() {
+            Some(NetTxToken { net : self })
+        }
+        else {
+            None
+        }
+    }
+
+    /// 未协商校验和卸载，收发的校验和都由 smoltcp 计算和检查
+    fn capabilities(&self)->DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = self.mtu() + ETHERNET_HEADER;
+        caps.checksum = ChecksumCapabilities::default();
+        caps
+    }
+}
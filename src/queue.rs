@@ -3,7 +3,7 @@
 //! 2021年3月29日 zg
 
 #![allow(dead_code)]
-use core::mem::size_of;
+use core::{mem::size_of, ptr::{read_volatile, write_volatile}, sync::atomic::{Ordering, fence}};
 
 use crate::config::PAGE_SIZE;
 
@@ -13,9 +13,24 @@ pub enum BlockFlag {
 	In = 0,
 	Out = 1,
 	Flush = 4,
+	GetId = 8,
 	Discard = 11,
 	WriteZeros = 13,
-	ReadOnly = 5,
+	SecureErase = 14,
+	ZoneAppend = 15,
+	ZoneReport = 16,
+	ZoneOpen = 18,
+	ZoneClose = 20,
+	ZoneFinish = 22,
+	ZoneReset = 24,
+	ZoneResetAll = 26,
+}
+
+impl BlockFlag {
+	/// 请求的数据段是否由设备写入
+	pub fn device_write(self)->bool {
+		matches!(self, BlockFlag::In | BlockFlag::GetId | BlockFlag::ZoneReport)
+	}
 }
 
 pub const VIRTIO_RING_SIZE : usize = 1 << 7;
@@ -31,6 +46,7 @@ pub struct VirtQueue {
 	pub used:     Used,
     desc_idx : u16,
 	used_idx : u16,
+	busy : [bool; VIRTIO_RING_SIZE],
 }
 
 #[repr(C)]
@@ -101,20 +117,37 @@ impl VirtQueue {
             next,
         };
         self.desc[self.desc_idx as usize] = desc;
+        self.busy[self.desc_idx as usize] = true;
         self.desc_idx = (self.desc_idx + 1) % VIRTIO_RING_SIZE as u16;
     }
 
-	pub fn add_avail(&mut self) {
-		self.avail.ring[self.avail.idx as usize % VIRTIO_RING_SIZE] = self.desc_idx;
-		self.avail.idx = self.avail.idx.wrapping_add(1);
+	/// 将写好的描述符链交给设备，须在链上的描述符全部写入后调用
+	/// 环中的表项先于 avail.idx 对设备可见，之后再通知设备
+	pub fn add_avail(&mut self, head : u16) {
+		let idx = self.avail.idx;
+		unsafe {write_volatile(&mut self.avail.ring[idx as usize % VIRTIO_RING_SIZE], head)}
+		fence(Ordering::Release);
+		unsafe {write_volatile(&mut self.avail.idx, idx.wrapping_add(1))}
+		fence(Ordering::SeqCst);
 	}
 
 	pub fn is_pending(&self)->bool {
-		self.used_idx != self.used.idx
+		self.used_idx != unsafe {read_volatile(&self.used.idx)}
+	}
+
+	/// 已提交的请求是否都已被设备归还并取出
+	pub fn is_idle(&self)->bool {
+		self.used_idx == self.avail.idx
+	}
+
+	/// 关闭后设备归还描述符时不发送中断，需要驱动轮询
+	pub fn set_interrupt(&mut self, enable : bool) {
+		let flags = if enable {0} else {VIRTIO_AVAIL_F_NO_INTERRUPT};
+		unsafe {write_volatile(&mut self.avail.flags, flags)}
 	}
 
 	pub fn next_elem(&mut self)->UsedElem {
-		let elem = self.used.ring[self.used_idx as usize % VIRTIO_RING_SIZE];
+		let elem = unsafe {read_volatile(&self.used.ring[self.used_idx as usize % VIRTIO_RING_SIZE])};
 		self.used_idx = self.used_idx.wrapping_add(1);
 		elem
 	}
@@ -122,6 +155,121 @@ impl VirtQueue {
 	pub fn desc_idx(&self)->u16 {
 		self.desc_idx
 	}
+
+	/// 从 desc_idx 开始的 num 个描述符是否都已被设备归还
+	pub fn is_free(&self, num : usize)->bool {
+		if num > VIRTIO_RING_SIZE {
+			return false;
+		}
+		(0..num).all(|i| {
+			!self.busy[(self.desc_idx as usize + i) % VIRTIO_RING_SIZE]
+		})
+	}
+
+	/// 从空闲描述符中取 num 个串成链并标记占用，返回链头，空闲描述符不足时返回 None
+	/// 跳过仍被占用的链，链头还须满足 head；描述符内容随后由 set_desc 依次写入
+	pub fn alloc_chain(&mut self, num : usize, mut head : impl FnMut(u16)->bool)->Option<u16> {
+		if num == 0 || self.busy.iter().filter(|busy| !**busy).count() < num {
+			return None;
+		}
+		let start = self.desc_idx as usize;
+		let first = (0..VIRTIO_RING_SIZE).map(|i| (start + i) % VIRTIO_RING_SIZE)
+			.find(|&i| !self.busy[i] && head(i as u16))?;
+		let mut prev = first;
+		let mut rest = num - 1;
+		self.busy[first] = true;
+		for i in 1..VIRTIO_RING_SIZE {
+			if rest == 0 {
+				break;
+			}
+			let idx = (first + i) % VIRTIO_RING_SIZE;
+			if !self.busy[idx] {
+				self.busy[idx] = true;
+				self.desc[prev].flags = DescFlag::Next as u16;
+				self.desc[prev].next = idx as u16;
+				prev = idx;
+				rest -= 1;
+			}
+		}
+		self.desc[prev].flags = 0;
+		self.desc_idx = ((prev + 1) % VIRTIO_RING_SIZE) as u16;
+		Some(first as u16)
+	}
+
+	/// 写入 alloc_chain 分配的链上的一个描述符，保留链接，返回链上的下一个
+	pub fn set_desc(&mut self, idx : u16, addr : u64, len : u32, flag : u16)->u16 {
+		let desc = &mut self.desc[idx as usize % VIRTIO_RING_SIZE];
+		desc.addr = addr;
+		desc.len = len;
+		desc.flags = (desc.flags & DescFlag::Next as u16) | (flag & !(DescFlag::Next as u16));
+		desc.next
+	}
+
+	/// 回收以 head 开头的描述符链
+	pub fn free_desc(&mut self, head : u16) {
+		let mut idx = head as usize % VIRTIO_RING_SIZE;
+		loop {
+			self.busy[idx] = false;
+			let desc = &self.desc[idx];
+			if desc.flags & DescFlag::Next as u16 == 0 {
+				break;
+			}
+			idx = desc.next as usize % VIRTIO_RING_SIZE;
+		}
+	}
 }
 
 
+
+#[cfg(test)]
+mod tests {
+	extern crate std;
+
+	use core::mem::zeroed;
+	use std::boxed::Box;
+
+	use super::*;
+
+	fn chain(queue : &VirtQueue, head : u16)->std::vec::Vec<u16> {
+		let mut idx = head;
+		let mut rt = std::vec![idx];
+		while queue.desc[idx as usize].flags & DescFlag::Next as u16 != 0 {
+			idx = queue.desc[idx as usize].next;
+			rt.push(idx);
+		}
+		rt
+	}
+
+	#[test]
+	fn alloc_skips_busy_chains() {
+		let mut queue : Box<VirtQueue> = Box::new(unsafe {zeroed()});
+		for i in 0..VIRTIO_RING_SIZE / 2 {
+			assert_eq!(queue.alloc_chain(2, |_| true), Some(i as u16 * 2));
+		}
+		assert_eq!(queue.alloc_chain(1, |_| true), None);
+		// 绕回后，仍被占用的链不影响其他链的分配
+		queue.free_desc(6);
+		queue.free_desc(20);
+		let head = queue.alloc_chain(3, |_| true).unwrap();
+		assert_eq!(chain(&queue, head), [6, 7, 20]);
+		assert_eq!(queue.alloc_chain(2, |_| true), None);
+		queue.free_desc(head);
+		let head = queue.alloc_chain(2, |_| true).unwrap();
+		assert_eq!(chain(&queue, head), [21, 6]);
+		queue.free_desc(21);
+		let head = queue.alloc_chain(2, |head| head != 7).unwrap();
+		assert_eq!(chain(&queue, head), [20, 21]);
+	}
+
+	#[test]
+	fn set_desc_keeps_links() {
+		let mut queue : Box<VirtQueue> = Box::new(unsafe {zeroed()});
+		let head = queue.alloc_chain(2, |_| true).unwrap();
+		let next = queue.set_desc(head, 0x1000, 16, DescFlag::Write as u16);
+		assert_eq!(next, 1);
+		queue.set_desc(next, 0x2000, 1, DescFlag::Write as u16 | DescFlag::Next as u16);
+		assert_eq!(queue.desc[0].flags, DescFlag::Next as u16 | DescFlag::Write as u16);
+		assert_eq!(queue.desc[1].flags, DescFlag::Write as u16);
+		assert_eq!(chain(&queue, head), [0, 1]);
+	}
+}
@@ -0,0 +1,120 @@
+//! # 内存盘
+//! 用一段内存模拟块设备，可以不依赖 virtio 磁盘启动，也可装载 initrd
+
+use core::ptr::write_bytes;
+
+use tisu_memory::MemoryOp;
+
+use crate::{InterruptResult, IoResult, config::{InterruptOk, IoError, PAGE_SIZE, SECTOR_SIZE}, require::{BlockDriver, Driver}};
+
+pub struct RamDisk {
+    data : *mut u8,
+    sectors : usize,
+    read_only : bool,
+}
+
+impl RamDisk {
+    /// 从内核申请 sectors 个扇区的内存并清零
+    pub fn new(sectors : usize, memory : &mut impl MemoryOp)->Result<Self, IoError> {
+        let size = sectors * SECTOR_SIZE;
+        let num = size.div_ceil(PAGE_SIZE);
+        let data = memory.kernel_page(num).ok_or(IoError::NoMemory)?;
+        unsafe {write_bytes(data, 0, size)}
+        Ok(Self {
+            data,
+            sectors,
+            read_only : false,
+        })
+    }
+
+    /// 使用已有的内存，不足一个扇区的尾部不使用
+    pub fn from_slice(data : &'static mut [u8])->Self {
+        Self {
+            sectors : data.len() / SECTOR_SIZE,
+            data : data as *mut [u8] as *mut u8,
+            read_only : false,
+        }
+    }
+
+    /// 使用只读内存，例如 initrd
+    pub fn from_static(data : &'static [u8])->Self {
+        Self {
+            sectors : data.len() / SECTOR_SIZE,
+            data : data as *const [u8] as *const u8 as *mut u8,
+            read_only : true,
+        }
+    }
+
+    fn fill_zero(&mut self, offset : usize, len : usize)->IoResult {
+        if self.read_only {
+            return Err(IoError::ReadOnly);
+        }
+        self.check_range(offset, len)?;
+        unsafe {write_bytes(self.data.add(offset), 0, len)}
+        Ok(())
+    }
+}
+
+impl Driver for RamDisk {
+    fn handler(&mut self)->InterruptResult {
+        Ok(InterruptOk::Block)
+    }
+
+    fn pending(&mut self)->InterruptResult {
+        Ok(InterruptOk::Block)
+    }
+}
+
+impl BlockDriver for RamDisk {
+    fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
+        if self.read_only {
+            return Err(IoError::ReadOnly);
+        }
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        unsafe {self.data.add(offset).copy_from(data.as_ptr(), len)}
+        Ok(())
+    }
+
+    fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+        if data.len() < len {
+            return Err(IoError::BufferTooSmall(len));
+        }
+        self.check_range(offset, len)?;
+        unsafe {data.as_mut_ptr().copy_from(self.data.add(offset), len)}
+        Ok(())
+    }
+
+    /// 被丢弃的数据读出为 0
+    fn discard(&mut self, offset : usize, len : usize)->IoResult {
+        self.fill_zero(offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset : usize, len : usize, _unmap : bool)->IoResult {
+        self.fill_zero(offset, len)
+    }
+
+    fn capacity(&self)->usize {
+        self.sectors
+    }
+
+    fn read_only(&self)->bool {
+        self.read_only
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use std::{boxed::Box, vec};
+
+    use super::*;
+
+    /// 测试共用的内存盘，内存直接泄漏
+    pub(crate) fn disk(sectors : usize)->RamDisk {
+        RamDisk::from_slice(Box::leak(vec![0;sectors * SECTOR_SIZE].into_boxed_slice()))
+    }
+}
@@ -2,18 +2,142 @@
 //! 
 //! 2021年4月14日 zg
 
-use crate::{GraphicResult, InterruptResult, IoResult, Rect, config::{Pixel}};
+use crate::{GraphicResult, InterruptResult, IoResult, NetResult, Rect, config::{IoError, NetError, PAGE_SIZE, Pixel, RequestId, SECTOR_SIZE}};
+
+/// 由内核实现，告知驱动当前运行在哪个 hart 上
+pub trait HartId {
+    fn hart_id(&self)->usize;
+}
+
+/// 由内核实现的时钟，单位由内核决定，只要求单调递增
+pub trait Clock {
+    fn now(&self)->u64;
+}
 
 pub trait Driver {
     /// 处理中断
     fn handler(&mut self)->InterruptResult;
     /// 通知中断
     fn pending(&mut self)->InterruptResult;
+    /// 轮询模式下代替中断调用，直接处理设备已完成的请求
+    fn poll(&mut self)->InterruptResult {
+        self.pending()?;
+        self.handler()
+    }
+    /// 开启后设备不再发送中断，等待请求时直接轮询，可在中断控制器初始化前使用
+    fn set_polling(&mut self, _polling : bool) {}
 }
 
 pub trait BlockDriver : Driver {
     fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult;
     fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult;
+    /// 将多个缓冲区依次写入从 offset 开始的连续扇区
+    fn sync_writev(&mut self, offset : usize, data : &[&[u8]])->IoResult {
+        let mut offset = offset;
+        for buffer in data {
+            self.sync_write(offset, buffer.len(), buffer)?;
+            offset += buffer.len();
+        }
+        Ok(())
+    }
+    /// 将从 offset 开始的连续扇区依次读入多个缓冲区
+    fn sync_readv(&mut self, offset : usize, data : &mut [&mut [u8]])->IoResult {
+        let mut offset = offset;
+        for buffer in data.iter_mut() {
+            let len = buffer.len();
+            self.sync_read(offset, len, buffer)?;
+            offset += len;
+        }
+        Ok(())
+    }
+    /// 等待之前完成的写请求落盘
+    fn flush(&mut self)->IoResult {
+        Ok(())
+    }
+    /// 告知设备该范围的数据不再使用，之后读出的内容不确定
+    fn discard(&mut self, _offset : usize, _len : usize)->IoResult {
+        Err(IoError::Unsupported)
+    }
+    /// 将范围内的数据清零，unmap 为真时允许设备同时回收空间
+    fn write_zeroes(&mut self, _offset : usize, _len : usize, _unmap : bool)->IoResult {
+        Err(IoError::Unsupported)
+    }
+    /// 先尝试 write_zeroes，设备不支持时退回按页写入零
+    fn fill_zeroes(&mut self, offset : usize, len : usize, unmap : bool)->IoResult {
+        match self.write_zeroes(offset, len, unmap) {
+            Err(IoError::Unsupported) => {}
+            rt => return rt,
+        }
+        let zero = [0;PAGE_SIZE];
+        let chunk = PAGE_SIZE / self.block_size() * self.block_size();
+        if chunk == 0 {
+            return Err(IoError::Unsupported);
+        }
+        let mut done = 0;
+        while done < len {
+            let n = chunk.min(len - done);
+            self.sync_write(offset + done, n, &zero[..n])?;
+            done += n;
+        }
+        Ok(())
+    }
+    /// 容量，以扇区（512 字节）为单位
+    fn capacity(&self)->usize;
+    /// 逻辑块大小，读写的偏移和长度须按此对齐
+    fn block_size(&self)->usize {
+        SECTOR_SIZE
+    }
+    /// 物理块大小，按此对齐读写性能最好
+    fn physical_block_size(&self)->usize {
+        self.block_size()
+    }
+    /// 设备是否只读
+    fn read_only(&self)->bool {
+        false
+    }
+    /// 单个请求最多包含的数据段数
+    fn max_segments(&self)->usize {
+        1
+    }
+    /// 单个数据段最大字节数
+    fn max_segment_size(&self)->usize {
+        usize::MAX
+    }
+    /// 检查请求是否对齐且不超出容量
+    fn check_range(&self, offset : usize, len : usize)->IoResult {
+        let size = self.block_size();
+        if !offset.is_multiple_of(size) || !len.is_multiple_of(size) {
+            return Err(IoError::Misaligned);
+        }
+        match offset.checked_add(len) {
+            Some(end) if end <= self.capacity() * SECTOR_SIZE => Ok(()),
+            _ => Err(IoError::OutOfRange),
+        }
+    }
+}
+
+/// 异步块设备，允许同时有多个请求在设备中处理
+pub trait AsyncBlockDriver : BlockDriver {
+    /// 提交写请求
+    ///
+    /// # Safety
+    /// 设备归还请求前 data 必须保持有效且不被修改，即使句柄被丢弃或等待超时
+    unsafe fn submit_write(&mut self, offset : usize, len : usize, data : &[u8])->Result<RequestId, IoError>;
+    /// 提交读请求
+    ///
+    /// # Safety
+    /// 设备归还请求前 data 必须保持有效且不被访问，即使句柄被丢弃或等待超时
+    unsafe fn submit_read(&mut self, offset : usize, len : usize, data : &mut [u8])->Result<RequestId, IoError>;
+    /// 查询请求结果，未完成返回 None；取回结果后句柄失效
+    fn try_wait(&mut self, id : RequestId)->Option<IoResult>;
+    /// 等待请求完成并取回结果
+    fn wait(&mut self, id : RequestId)->IoResult;
+    /// 等待请求直到时钟到达 deadline，超时则放弃请求并返回 IoError::Timeout
+    /// 被放弃的请求迟到的完成会被忽略，提交时的缓冲区在设备归还请求前仍可能被访问
+    fn wait_until(&mut self, id : RequestId, deadline : u64)->IoResult;
+    /// 不再等待请求，已完成的立即回收，未完成的在设备归还后回收，句柄随即失效
+    /// 提交时的缓冲区在设备归还请求前仍可能被访问
+    fn cancel(&mut self, id : RequestId);
 }
 
 pub trait GraphicDriver : Driver {
@@ -23,6 +147,10 @@ pub trait GraphicDriver : Driver {
 }
 
 pub trait NetDriver : Driver {
-    fn send(&mut self, data : &[u8]);
+    /// 将帧复制到驱动的缓冲区后发送，返回后 data 即可重用
+    fn send(&mut self, data : &[u8])->NetResult;
+    /// 取出一个收到的帧（不含 virtio-net 头部），返回帧长度，没有帧时返回 None
+    /// 缓冲区不足时帧仍保留，可换更大的缓冲区重试
+    fn receive(&mut self, data : &mut [u8])->Option<Result<usize, NetError>>;
     fn mac(&self)->usize;
 }
@@ -0,0 +1,224 @@
+//! # 块请求调度
+//! 请求先排队，下发时按扇区排序，地址相邻的同向请求合并为一个多段请求
+
+use core::{marker::PhantomData, mem::ManuallyDrop, ptr::{null_mut, read}, slice::{from_raw_parts, from_raw_parts_mut}};
+
+use crate::{InterruptResult, IoResult, config::IoError, require::{BlockDriver, Driver}};
+
+/// 最多排队的请求数
+const QUEUE_SIZE : usize = 64;
+/// 合并后单个请求最多包含的缓冲区数
+const MERGE_MAX : usize = 32;
+
+#[derive(Clone, Copy)]
+struct Pending {
+    offset : usize,
+    data : *mut u8,
+    len : usize,
+    write : bool,
+}
+
+impl Pending {
+    fn end(&self)->usize {
+        self.offset + self.len
+    }
+
+    fn overlap(&self, offset : usize, len : usize)->bool {
+        self.offset < offset + len && offset < self.end()
+    }
+}
+
+impl Default for Pending {
+    fn default() -> Self {
+        Self {
+            offset : 0,
+            data : null_mut(),
+            len : 0,
+            write : false,
+        }
+    }
+}
+
+/// 请求调度器，排队的缓冲区在下发前必须保持有效，由生命周期 'a 保证
+/// 丢弃时下发剩余请求，此时的错误无法报告，需要结果时应先调用 dispatch 或 into_inner
+pub struct Scheduler<'a, D : BlockDriver> {
+    driver : D,
+    queue : [Pending; QUEUE_SIZE],
+    num : usize,
+    _data : PhantomData<&'a mut [u8]>,
+}
+
+impl<'a, D : BlockDriver> Scheduler<'a, D> {
+    pub fn new(driver : D)->Self {
+        Self {
+            driver,
+            queue : [Pending::default(); QUEUE_SIZE],
+            num : 0,
+            _data : PhantomData,
+        }
+    }
+
+    /// 下发所有排队请求后取回设备
+    pub fn into_inner(mut self)->Result<D, IoError> {
+        self.dispatch()?;
+        let this = ManuallyDrop::new(self);
+        Ok(unsafe {read(&this.driver)})
+    }
+
+    /// 排队一个写请求
+    pub fn queue_write(&mut self, offset : usize, data : &'a [u8])->IoResult {
+        if self.driver.read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        let len = data.len();
+        self.push(offset, data as *const [u8] as *const u8 as *mut u8, len, true)
+    }
+
+    /// 排队一个读请求，数据在 dispatch 后才可用
+    pub fn queue_read(&mut self, offset : usize, data : &'a mut [u8])->IoResult {
+        let len = data.len();
+        self.push(offset, data as *mut [u8] as *mut u8, len, false)
+    }
+
+    fn push(&mut self, offset : usize, data : *mut u8, len : usize, write : bool)->IoResult {
+        self.driver.check_range(offset, len)?;
+        // 与排队中的请求重叠时先下发，保证读写顺序
+        let overlap = self.queue[..self.num].iter().any(|p| {
+            (write || p.write) && p.overlap(offset, len)
+        });
+        if overlap || self.num == QUEUE_SIZE {
+            self.dispatch()?;
+        }
+        self.queue[self.num] = Pending { offset, data, len, write };
+        self.num += 1;
+        Ok(())
+    }
+
+    /// 按扇区排序、合并后下发全部排队请求
+    /// 出错时丢弃失败的一组，其后未下发的请求留在队列中
+    pub fn dispatch(&mut self)->IoResult {
+        let num = self.num;
+        // 插入排序，相同偏移保持提交顺序
+        for i in 1..num {
+            let mut j = i;
+            while j > 0 && self.queue[j - 1].offset > self.queue[j].offset {
+                self.queue.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+        let mut i = 0;
+        while i < num {
+            let first = self.queue[i];
+            let mut j = i + 1;
+            while j < num && j - i < MERGE_MAX &&
+                    self.queue[j].write == first.write &&
+                    self.queue[j].offset == self.queue[j - 1].end() {
+                j += 1;
+            }
+            let rt = if first.write {
+                let mut data : [&[u8]; MERGE_MAX] = Default::default();
+                for (k, p) in self.queue[i..j].iter().enumerate() {
+                    data[k] = unsafe {from_raw_parts(p.data, p.len)};
+                }
+                self.driver.sync_writev(first.offset, &data[..j - i])
+            }
+            else {
+                let mut data : [&mut [u8]; MERGE_MAX] = Default::default();
+                for (k, p) in self.queue[i..j].iter().enumerate() {
+                    data[k] = unsafe {from_raw_parts_mut(p.data, p.len)};
+                }
+                self.driver.sync_readv(first.offset, &mut data[..j - i])
+            };
+            if rt.is_err() {
+                self.queue.copy_within(j..num, 0);
+                self.num = num - j;
+                return rt;
+            }
+            i = j;
+        }
+        self.num = 0;
+        Ok(())
+    }
+}
+
+impl<'a, D : BlockDriver> Drop for Scheduler<'a, D> {
+    fn drop(&mut self) {
+        let _ = self.dispatch();
+    }
+}
+
+impl<'a, D : BlockDriver> Driver for Scheduler<'a, D> {
+    fn handler(&mut self)->InterruptResult {
+        self.driver.handler()
+    }
+
+    fn pending(&mut self)->InterruptResult {
+        self.driver.pending()
+    }
+
+    fn set_polling(&mut self, polling : bool) {
+        self.driver.set_polling(polling)
+    }
+}
+
+/// 同步接口无法延后下发，先清空队列再直接交给设备
+impl<'a, D : BlockDriver> BlockDriver for Scheduler<'a, D> {
+    fn sync_write(&mut self, offset : usize, len : usize, data : &[u8])->IoResult {
+        self.dispatch()?;
+        self.driver.sync_write(offset, len, data)
+    }
+
+    fn sync_read(&mut self, offset : usize, len : usize, data : &mut [u8])->IoResult {
+        self.dispatch()?;
+        self.driver.sync_read(offset, len, data)
+    }
+
+    fn sync_writev(&mut self, offset : usize, data : &[&[u8]])->IoResult {
+        self.dispatch()?;
+        self.driver.sync_writev(offset, data)
+    }
+
+    fn sync_readv(&mut self, offset : usize, data : &mut [&mut [u8]])->IoResult {
+        self.dispatch()?;
+        self.driver.sync_readv(offset, data)
+    }
+
+    fn flush(&mut self)->IoResult {
+        self.dispatch()?;
+        self.driver.flush()
+    }
+
+    fn discard(&mut self, offset : usize, len : usize)->IoResult {
+        self.dispatch()?;
+        self.driver.discard(offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset : usize, len : usize, unmap : bool)->IoResult {
+        self.dispatch()?;
+        self.driver.write_zeroes(offset, len, unmap)
+    }
+
+    fn capacity(&self)->usize {
+        self.driver.capacity()
+    }
+
+    fn block_size(&self)->usize {
+        self.driver.block_size()
+    }
+
+    fn physical_block_size(&self)->usize {
+        self.driver.physical_block_size()
+    }
+
+    fn read_only(&self)->bool {
+        self.driver.read_only()
+    }
+
+    fn max_segments(&self)->usize {
+        self.driver.max_segments()
+    }
+
+    fn max_segment_size(&self)->usize {
+        self.driver.max_segment_size()
+    }
+}